@@ -0,0 +1,8 @@
+//! Test helpers that several examples share, as a dev-dependency by path: a
+//! `BPF_PROG_TEST_RUN` harness, and a network namespace to run an example's
+//! binary in end to end.
+
+pub mod netns;
+mod test_run;
+
+pub use test_run::{test_run, test_run_with_ctx};
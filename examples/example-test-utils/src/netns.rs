@@ -0,0 +1,260 @@
+//! Runs an example's binary on one end of a veth pair in a network namespace
+//! of its own, and sends it frames from the other end. Deleting the namespace
+//! deletes the pair, and with it any program left attached, so no real
+//! interface or network is touched.
+
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, BufRead as _, BufReader, Read},
+    mem,
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The end the program is attached to.
+pub const XDP_IFACE: &str = "veth-xdp";
+/// The end frames are sent from.
+pub const PEER_IFACE: &str = "veth-peer";
+
+/// How long to wait for the binary to print something.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip").args(args).status().unwrap();
+    assert!(status.success(), "ip {}: {status}", args.join(" "));
+}
+
+/// A network namespace holding a veth pair.
+pub struct Netns {
+    name: String,
+    /// An `AF_PACKET` socket in the namespace, for sending frames.
+    socket: OwnedFd,
+    peer_index: u32,
+}
+
+impl Netns {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "xdp-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        ip(&["netns", "add", &name]);
+        let netns = |args: &[&str]| {
+            let status = Command::new("ip")
+                .args(["netns", "exec", &name])
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{}: {status}", args.join(" "));
+        };
+        // Without IPv6, the kernel sends nothing on the interfaces by itself,
+        // so the binary only sees the frames a test sends.
+        netns(&["sysctl", "-qw", "net.ipv6.conf.all.disable_ipv6=1"]);
+        netns(&["sysctl", "-qw", "net.ipv6.conf.default.disable_ipv6=1"]);
+        ip(&[
+            "-n", &name, "link", "add", XDP_IFACE, "type", "veth", "peer",
+            "name", PEER_IFACE,
+        ]);
+        ip(&["-n", &name, "link", "set", XDP_IFACE, "up"]);
+        ip(&["-n", &name, "link", "set", PEER_IFACE, "up"]);
+        let (socket, peer_index) = Self::open_socket(&name);
+        Self {
+            name,
+            socket,
+            peer_index,
+        }
+    }
+
+    /// Opens a raw packet socket in the namespace and looks up the peer's
+    /// index there. Entering a namespace only affects the calling thread, and
+    /// the socket stays in the namespace it was created in.
+    fn open_socket(name: &str) -> (OwnedFd, u32) {
+        let netns = File::open(format!("/var/run/netns/{name}")).unwrap();
+        thread::scope(|scope| {
+            scope
+                .spawn(|| unsafe {
+                    let ret =
+                        libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET);
+                    assert_eq!(ret, 0, "setns: {}", io::Error::last_os_error());
+                    let fd = libc::socket(
+                        libc::AF_PACKET,
+                        libc::SOCK_RAW,
+                        0, // only for sending, so no protocol
+                    );
+                    assert!(fd >= 0, "socket: {}", io::Error::last_os_error());
+                    let peer = CString::new(PEER_IFACE).unwrap();
+                    let index = libc::if_nametoindex(peer.as_ptr());
+                    assert_ne!(index, 0);
+                    (OwnedFd::from_raw_fd(fd), index)
+                })
+                .join()
+                .unwrap()
+        })
+    }
+
+    /// Sends `frame` out of the peer, so that it arrives on [`XDP_IFACE`].
+    pub fn send(&self, frame: &[u8]) {
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_ifindex = self.peer_index as i32;
+        let sent = unsafe {
+            libc::sendto(
+                self.socket.as_raw_fd(),
+                frame.as_ptr().cast(),
+                frame.len(),
+                0,
+                (&raw const addr).cast(),
+                size_of::<libc::sockaddr_ll>() as u32,
+            )
+        };
+        assert_eq!(
+            sent,
+            frame.len() as isize,
+            "sendto: {}",
+            io::Error::last_os_error()
+        );
+    }
+
+    /// Runs `binary` in the namespace with `args` until it exits, checks that
+    /// it succeeded and returns its stdout.
+    pub fn run(&self, binary: &str, args: &[&str]) -> String {
+        let output = Command::new("ip")
+            .args(["netns", "exec", &self.name, binary])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}: {}\n{}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// Runs `binary` in the namespace with `args`, logging at the info level,
+    /// and waits until it's attached.
+    pub fn spawn(&self, binary: &str, args: &[&str]) -> Running {
+        let mut child = Command::new("ip")
+            .args(["netns", "exec", &self.name, binary])
+            .args(args)
+            .env("RUST_LOG", "info")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let (sender, lines) = mpsc::channel();
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        for output in
+            [Box::new(stdout) as Box<dyn Read + Send>, Box::new(stderr)]
+        {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(output).lines() {
+                    let Ok(line) = line else { break };
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        let mut running = Running {
+            child,
+            lines,
+            output: Vec::new(),
+        };
+        running.wait_for(|line| line.contains("Waiting for Ctrl-C"));
+        running
+    }
+}
+
+impl Drop for Netns {
+    fn drop(&mut self) {
+        // Deleting the namespace deletes the veth pair in it.
+        let _ = Command::new("ip")
+            .args(["netns", "del", &self.name])
+            .status();
+    }
+}
+
+/// The binary, running in a [`Netns`]. It's stopped when dropped, if
+/// [`Running::stop`] wasn't called.
+pub struct Running {
+    child: Child,
+    /// Lines of stdout and stderr, interleaved as they arrive.
+    lines: Receiver<String>,
+    /// Every line read so far, for error messages.
+    output: Vec<String>,
+}
+
+impl Running {
+    /// Every line of output read so far.
+    pub fn output(&self) -> &[String] {
+        &self.output
+    }
+
+    /// Reads output until a line matches `pred`, and returns that line.
+    pub fn wait_for(&mut self, mut pred: impl FnMut(&str) -> bool) -> String {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(timeout) {
+                Ok(line) => {
+                    self.output.push(line.clone());
+                    if pred(&line) {
+                        return line;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    panic!(
+                        "timed out, output so far:\n{}",
+                        self.output.join("\n")
+                    )
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    panic!("exited, output:\n{}", self.output.join("\n"))
+                }
+            }
+        }
+    }
+
+    /// Stops the binary as Ctrl-C would, checks that it exited cleanly and
+    /// returns the rest of its output.
+    pub fn stop(mut self) -> Vec<String> {
+        self.interrupt();
+        let status = self.child.wait().unwrap();
+        let rest: Vec<_> = self.lines.iter().collect();
+        self.output.extend(rest.iter().cloned());
+        assert!(
+            status.success(),
+            "{status}, output:\n{}",
+            self.output.join("\n")
+        );
+        rest
+    }
+
+    fn interrupt(&self) {
+        // `ip netns exec` execs the binary, so this is the binary's PID.
+        unsafe { libc::kill(self.child.id() as i32, libc::SIGINT) };
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            self.interrupt();
+            let _ = self.child.wait();
+        }
+    }
+}
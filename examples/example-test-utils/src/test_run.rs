@@ -0,0 +1,78 @@
+//! Running a loaded program once with `BPF_PROG_TEST_RUN`, over a
+//! caller-supplied packet and without attaching it anywhere. This needs
+//! `CAP_BPF`, hence the `sudo -E` runner in each example's
+//! `.cargo/config.toml`.
+
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd as _},
+    ptr,
+};
+
+const BPF_PROG_TEST_RUN: libc::c_long = 10;
+
+/// The `test` member of `union bpf_attr`. Left at zero, `repeat` runs the
+/// program once.
+#[repr(C)]
+#[derive(Default)]
+struct TestRunAttr {
+    prog_fd: u32,
+    retval: u32,
+    data_size_in: u32,
+    data_size_out: u32,
+    data_in: u64,
+    data_out: u64,
+    repeat: u32,
+    duration: u32,
+    ctx_size_in: u32,
+    ctx_size_out: u32,
+    ctx_in: u64,
+    ctx_out: u64,
+}
+
+/// Runs `program` once over `data` and returns what it returned, e.g. the XDP
+/// action it chose.
+pub fn test_run(program: impl AsFd, data: &[u8]) -> u32 {
+    run(program, data, TestRunAttr::default())
+}
+
+/// Like [`test_run`], also passing in `ctx` as the program's context, e.g. the
+/// start of a `__sk_buff`, and reading back what the program left in it.
+pub fn test_run_with_ctx<C>(
+    program: impl AsFd,
+    data: &[u8],
+    ctx: &mut C,
+) -> u32 {
+    let size = size_of::<C>() as u32;
+    let ctx = ptr::from_mut(ctx) as u64;
+    run(
+        program,
+        data,
+        TestRunAttr {
+            ctx_size_in: size,
+            ctx_size_out: size,
+            ctx_in: ctx,
+            ctx_out: ctx,
+            ..Default::default()
+        },
+    )
+}
+
+fn run(program: impl AsFd, data: &[u8], attr: TestRunAttr) -> u32 {
+    let mut attr = TestRunAttr {
+        prog_fd: program.as_fd().as_raw_fd() as u32,
+        data_size_in: data.len() as u32,
+        data_in: data.as_ptr() as u64,
+        ..attr
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_PROG_TEST_RUN,
+            &mut attr as *mut TestRunAttr,
+            size_of::<TestRunAttr>(),
+        )
+    };
+    assert_eq!(ret, 0, "BPF_PROG_TEST_RUN: {}", io::Error::last_os_error());
+    attr.retval
+}
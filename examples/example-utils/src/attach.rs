@@ -0,0 +1,122 @@
+//! Attaching an XDP program to several interfaces at once.
+
+use std::{fs, io};
+
+use anyhow::Context as _;
+use aya::programs::{Xdp, XdpMode, xdp::XdpLinkId};
+use clap::ValueEnum;
+use log::{info, warn};
+
+/// `IFF_LOOPBACK` from `<net/if.h>`.
+const IFF_LOOPBACK: u32 = 0x8;
+
+/// The interfaces to attach to: those given with `--iface`, or with `--all`,
+/// every interface other than loopback.
+pub fn interfaces(names: &[String], all: bool) -> anyhow::Result<Vec<String>> {
+    if !all {
+        return Ok(names.to_vec());
+    }
+    let names = all_interfaces().context("failed to list interfaces")?;
+    anyhow::ensure!(!names.is_empty(), "no interfaces to attach to");
+    Ok(names)
+}
+
+fn all_interfaces() -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir("/sys/class/net")? {
+        let entry = entry?;
+        let flags = fs::read_to_string(entry.path().join("flags"))?;
+        let flags =
+            u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if flags & IFF_LOOPBACK == 0 {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Where in the receive path the program runs.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum AttachMode {
+    /// Driver mode where the driver supports it, SKB mode otherwise.
+    Auto,
+    /// In the network driver, before the kernel allocates an skb. Not every
+    /// driver supports it.
+    Drv,
+    /// Generic mode, after the kernel allocates an skb. Works everywhere, but
+    /// is slower.
+    Skb,
+    /// Offloaded to the network card. Very few cards support it.
+    Hw,
+}
+
+/// The program attached to an interface.
+pub struct Attachment {
+    pub iface: String,
+    /// "driver", "SKB" or "hardware".
+    pub mode: &'static str,
+    pub link_id: XdpLinkId,
+}
+
+/// Attaches `program` to `iface` in `mode`, naming the mode in the error.
+fn attach(
+    program: &mut Xdp,
+    iface: &str,
+    mode: XdpMode,
+    name: &'static str,
+) -> anyhow::Result<(XdpLinkId, &'static str)> {
+    let link_id = program.attach(iface, mode).with_context(|| {
+        format!("failed to attach the XDP program to {iface} in {name} mode")
+    })?;
+    Ok((link_id, name))
+}
+
+/// Attaches `program` to each of `ifaces` in `mode`. In auto mode, an
+/// interface whose driver doesn't support XDP is attached in SKB mode instead,
+/// and the reason logged.
+///
+/// If an attach fails, the interfaces attached so far are detached when the
+/// program is dropped.
+pub fn attach_all(
+    program: &mut Xdp,
+    ifaces: &[String],
+    mode: AttachMode,
+) -> anyhow::Result<Vec<Attachment>> {
+    let mut attachments = Vec::new();
+    for iface in ifaces {
+        let (link_id, mode) = match mode {
+            AttachMode::Auto => {
+                match attach(program, iface, XdpMode::Drv, "driver") {
+                    Ok(attached) => attached,
+                    Err(e) => {
+                        warn!("{e:#}, falling back to SKB mode");
+                        attach(program, iface, XdpMode::Skb, "SKB")?
+                    }
+                }
+            }
+            AttachMode::Drv => attach(program, iface, XdpMode::Drv, "driver")?,
+            AttachMode::Skb => attach(program, iface, XdpMode::Skb, "SKB")?,
+            AttachMode::Hw => attach(program, iface, XdpMode::Hw, "hardware")?,
+        };
+        info!("attached to {iface} in {mode} mode");
+        attachments.push(Attachment {
+            iface: iface.clone(),
+            mode,
+            link_id,
+        });
+    }
+    Ok(attachments)
+}
+
+/// Detaches `program` from every interface in `attachments`, carrying on past
+/// failures so that one stuck interface doesn't leave the others attached.
+pub fn detach_all(program: &mut Xdp, attachments: Vec<Attachment>) {
+    for Attachment { iface, link_id, .. } in attachments {
+        match program.detach(link_id) {
+            Ok(()) => info!("detached from {iface}"),
+            Err(e) => warn!("failed to detach from {iface}: {e}"),
+        }
+    }
+}
@@ -0,0 +1,5 @@
+//! Code that several examples share. Each example is a workspace of its own,
+//! so they depend on this crate by path rather than copying it.
+
+pub mod attach;
+pub mod prefix;
@@ -0,0 +1,74 @@
+//! IPv4 and IPv6 prefixes, as given on the command line and in blocklist
+//! files.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, ensure};
+
+/// An IPv4 or IPv6 network in CIDR notation.
+///
+/// Host bits are cleared on construction, so `10.1.2.3/8` and `10.0.0.0/8`
+/// are the same prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Prefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Prefix {
+    pub fn new(addr: IpAddr, len: u8) -> anyhow::Result<Self> {
+        let addr = match addr {
+            IpAddr::V4(addr) => {
+                ensure!(len <= 32, "prefix length {len} is longer than 32");
+                let mask =
+                    u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+                Ipv4Addr::from(u32::from(addr) & mask).into()
+            }
+            IpAddr::V6(addr) => {
+                ensure!(len <= 128, "prefix length {len} is longer than 128");
+                let mask =
+                    u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+                Ipv6Addr::from(u128::from(addr) & mask).into()
+            }
+        };
+        Ok(Self { addr, len })
+    }
+
+    /// The network address.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = anyhow::Error;
+
+    /// Parses `addr/len`, or a bare address as a single host.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| anyhow!("invalid IP address {addr}: {e}"))?;
+        let len = if len.is_empty() {
+            if addr.is_ipv4() { 32 } else { 128 }
+        } else {
+            len.parse()
+                .map_err(|e| anyhow!("invalid prefix length {len}: {e}"))?
+        };
+        Self::new(addr, len)
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
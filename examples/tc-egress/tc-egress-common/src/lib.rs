@@ -9,3 +9,48 @@ pub struct PacketLog {
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for PacketLog {}
+
+/// Indexes of the `STATS` map, one per direction.
+pub mod direction {
+    pub const INGRESS: u32 = 0;
+    pub const EGRESS: u32 = 1;
+}
+
+/// Number of entries in the `STATS` map.
+pub const STATS_ENTRIES: u32 = 2;
+
+/// Packets a classifier passed and dropped.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+    pub passed: u64,
+    pub dropped: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Stats {}
+
+/// Number of entries in the `DEPARTURES` map, and so the most destination
+/// prefixes that can be shaped.
+pub const MAX_SHAPERS: u32 = 64;
+
+/// A rate limit on traffic to a destination prefix: the value of the `SHAPING`
+/// and `SHAPING_V6` maps.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Shaper {
+    /// Index of the prefix's entry in `DEPARTURES`.
+    pub id: u32,
+    _pad: u32,
+    /// Bytes per second.
+    pub rate: u64,
+}
+
+impl Shaper {
+    pub fn new(id: u32, rate: u64) -> Self {
+        Self { id, _pad: 0, rate }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Shaper {}
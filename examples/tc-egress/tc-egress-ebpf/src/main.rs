@@ -2,49 +2,225 @@
 #![no_main]
 
 use aya_ebpf::{
-    bindings::{TC_ACT_PIPE, TC_ACT_SHOT},
+    bindings::{BPF_F_NO_PREALLOC, TC_ACT_PIPE, TC_ACT_SHOT},
+    helpers::bpf_ktime_get_ns,
     macros::{classifier, map},
-    maps::HashMap,
+    maps::{Array, LpmTrie, PerCpuArray, lpm_trie::Key},
     programs::TcContext,
 };
 use aya_log_ebpf::info;
 use network_types::{
     eth::{EthHdr, EtherType},
-    ip::Ipv4Hdr,
+    ip::{Ipv4Hdr, Ipv6Hdr},
 };
+use tc_egress_common::{MAX_SHAPERS, STATS_ENTRIES, Shaper, Stats, direction};
 
 #[map]
-static BLOCKLIST: HashMap<u32, u32> = HashMap::with_max_entries(1024, 0);
+static BLOCKLIST: LpmTrie<u32, u32> =
+    LpmTrie::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+#[map]
+static BLOCKLIST_V6: LpmTrie<[u8; 16], u32> =
+    LpmTrie::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+/// Source prefixes dropped by `tc_ingress`.
+#[map]
+static INGRESS_BLOCKLIST: LpmTrie<u32, u32> =
+    LpmTrie::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+#[map]
+static INGRESS_BLOCKLIST_V6: LpmTrie<[u8; 16], u32> =
+    LpmTrie::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+/// Rate limits on traffic to destination prefixes.
+#[map]
+static SHAPING: LpmTrie<u32, Shaper> =
+    LpmTrie::with_max_entries(MAX_SHAPERS, BPF_F_NO_PREALLOC);
+
+#[map]
+static SHAPING_V6: LpmTrie<[u8; 16], Shaper> =
+    LpmTrie::with_max_entries(MAX_SHAPERS, BPF_F_NO_PREALLOC);
+
+/// The earliest departure time of the next packet to each shaped prefix, in
+/// nanoseconds of `CLOCK_MONOTONIC`, indexed by `Shaper::id`.
+#[map]
+static DEPARTURES: Array<u64> = Array::with_max_entries(MAX_SHAPERS, 0);
+
+#[map]
+static STATS: PerCpuArray<Stats> =
+    PerCpuArray::with_max_entries(STATS_ENTRIES, 0);
 
 #[classifier]
 pub fn tc_egress(ctx: TcContext) -> i32 {
-    match try_tc_egress(ctx) {
+    let action = match try_tc_egress(ctx) {
         Ok(ret) => ret,
         Err(_) => TC_ACT_SHOT,
+    };
+    count(direction::EGRESS, action);
+    action
+}
+
+#[classifier]
+pub fn tc_ingress(ctx: TcContext) -> i32 {
+    let action = match try_tc_ingress(ctx) {
+        Ok(ret) => ret,
+        Err(_) => TC_ACT_SHOT,
+    };
+    count(direction::INGRESS, action);
+    action
+}
+
+fn count(direction: u32, action: i32) {
+    if let Some(stats) = STATS.get_ptr_mut(direction) {
+        // The map is per-CPU, so nothing else is updating this entry.
+        let stats = unsafe { &mut *stats };
+        if action == TC_ACT_SHOT {
+            stats.dropped += 1;
+        } else {
+            stats.passed += 1;
+        }
     }
 }
 
-fn block_ip(address: u32) -> bool {
-    unsafe { BLOCKLIST.get(&address).is_some() }
+#[derive(Clone, Copy)]
+enum Address {
+    V4(u32),
+    V6([u8; 16]),
 }
 
-fn try_tc_egress(ctx: TcContext) -> Result<i32, ()> {
+/// Whether `address` falls in one of the prefixes in `v4` or `v6`.
+fn listed(
+    v4: &LpmTrie<u32, u32>,
+    v6: &LpmTrie<[u8; 16], u32>,
+    address: Address,
+) -> bool {
+    match address {
+        // LPM trie keys are compared bit by bit from the most significant
+        // byte, so the address has to be stored in network byte order.
+        Address::V4(address) => v4.get(&Key::new(32, address.to_be())),
+        Address::V6(address) => v6.get(&Key::new(128, address)),
+    }
+    .is_some()
+}
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// How far in the future a packet may be scheduled before it's dropped rather
+/// than queued. It's below the 10 second horizon of the fq qdisc, which would
+/// drop the packet anyway.
+const HORIZON_NS: u64 = 2 * NS_PER_SEC;
+
+fn shaper(destination: Address) -> Option<&'static Shaper> {
+    match destination {
+        Address::V4(address) => SHAPING.get(&Key::new(32, address.to_be())),
+        Address::V6(address) => SHAPING_V6.get(&Key::new(128, address)),
+    }
+}
+
+/// Holds back packets to a shaped prefix so that it doesn't receive more than
+/// its rate, using earliest departure time (EDT): each packet's `tstamp` is
+/// set to when it may leave, and the fq qdisc holds it until then. Returns
+/// the action for the packet.
+fn shape(ctx: &TcContext, destination: Address) -> i32 {
+    let Some(shaper) = shaper(destination) else {
+        return TC_ACT_PIPE;
+    };
+    let Some(departure) = DEPARTURES.get_ptr_mut(shaper.id) else {
+        return TC_ACT_PIPE;
+    };
+    // User space never stores a zero rate, but anything can write the map.
+    let Some(delay) =
+        (u64::from(ctx.len()) * NS_PER_SEC).checked_div(shaper.rate)
+    else {
+        return TC_ACT_PIPE;
+    };
+    let now = unsafe { bpf_ktime_get_ns() };
+    let skb = ctx.skb.skb;
+    // A packet may already be scheduled, by another program or the stack.
+    let tstamp = unsafe { (*skb).tstamp }.max(now);
+    // Packets to the same prefix on other CPUs can race with this, which only
+    // lets a little more through than the rate allows.
+    let next = unsafe { *departure };
+    if next <= tstamp {
+        // The prefix has been idle, so the packet can leave when it would have.
+        unsafe { *departure = tstamp + delay };
+        return TC_ACT_PIPE;
+    }
+    if next - now > HORIZON_NS {
+        return TC_ACT_SHOT;
+    }
+    unsafe {
+        *departure = next + delay;
+        (*skb).tstamp = next;
+    }
+    TC_ACT_PIPE
+}
+
+/// Loads the source and destination addresses of an IPv4 or IPv6 packet, or
+/// returns `None` for any other frame.
+fn addresses(ctx: &TcContext) -> Result<Option<(Address, Address)>, ()> {
     let ethhdr: EthHdr = ctx.load(0).map_err(|_| ())?;
     match ethhdr.ether_type() {
-        Ok(EtherType::Ipv4) => {}
-        _ => return Ok(TC_ACT_PIPE),
+        Ok(EtherType::Ipv4) => {
+            let ipv4hdr: Ipv4Hdr = ctx.load(EthHdr::LEN).map_err(|_| ())?;
+            Ok(Some((
+                Address::V4(u32::from_be_bytes(ipv4hdr.src_addr)),
+                Address::V4(u32::from_be_bytes(ipv4hdr.dst_addr)),
+            )))
+        }
+        Ok(EtherType::Ipv6) => {
+            let ipv6hdr: Ipv6Hdr = ctx.load(EthHdr::LEN).map_err(|_| ())?;
+            Ok(Some((
+                Address::V6(ipv6hdr.src_addr),
+                Address::V6(ipv6hdr.dst_addr),
+            )))
+        }
+        _ => Ok(None),
     }
+}
 
-    let ipv4hdr: Ipv4Hdr = ctx.load(EthHdr::LEN).map_err(|_| ())?;
-    let destination = u32::from_be_bytes(ipv4hdr.dst_addr);
+fn try_tc_egress(ctx: TcContext) -> Result<i32, ()> {
+    let Some((_, destination)) = addresses(&ctx)? else {
+        return Ok(TC_ACT_PIPE);
+    };
 
-    let action = if block_ip(destination) {
+    let action = if listed(&BLOCKLIST, &BLOCKLIST_V6, destination) {
+        TC_ACT_SHOT
+    } else {
+        shape(&ctx, destination)
+    };
+
+    match destination {
+        Address::V4(address) => {
+            info!(&ctx, "DEST {:i}, ACTION {}", address, action)
+        }
+        Address::V6(address) => {
+            info!(&ctx, "DEST {:i}, ACTION {}", address, action)
+        }
+    }
+
+    Ok(action)
+}
+
+fn try_tc_ingress(ctx: TcContext) -> Result<i32, ()> {
+    let Some((source, _)) = addresses(&ctx)? else {
+        return Ok(TC_ACT_PIPE);
+    };
+
+    let action = if listed(&INGRESS_BLOCKLIST, &INGRESS_BLOCKLIST_V6, source) {
         TC_ACT_SHOT
     } else {
         TC_ACT_PIPE
     };
 
-    info!(&ctx, "DEST {:i}, ACTION {}", destination, action);
+    match source {
+        Address::V4(address) => {
+            info!(&ctx, "SRC {:i}, ACTION {}", address, action)
+        }
+        Address::V6(address) => {
+            info!(&ctx, "SRC {:i}, ACTION {}", address, action)
+        }
+    }
 
     Ok(action)
 }
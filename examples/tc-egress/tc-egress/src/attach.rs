@@ -0,0 +1,160 @@
+//! Attaching the classifiers with TCX where the kernel supports it, and to a
+//! clsact qdisc otherwise.
+
+use std::io;
+
+use anyhow::Context as _;
+use aya::programs::{
+    LinkOrder, ProgramId, ProgramInfo, SchedClassifier, TcAttachType,
+    tc::{NlOptions, SchedClassifierLinkId, TcAttachOptions},
+};
+use clap::ValueEnum;
+use log::warn;
+
+use crate::clsact;
+
+/// How the classifiers are attached.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum AttachMethod {
+    /// TCX where the kernel supports it (6.6 and later), clsact otherwise.
+    Auto,
+    /// A TCX link, which other programs on the hook can be ordered around.
+    Tcx,
+    /// A filter on the clsact qdisc, which is added if it's missing.
+    Clsact,
+}
+
+/// Where a TCX link goes among the programs already attached to the hook.
+#[derive(Clone, Copy, Debug)]
+pub enum Order {
+    First,
+    Last,
+    /// Before the program with this ID.
+    Before(u32),
+    /// After the program with this ID.
+    After(u32),
+}
+
+impl Order {
+    fn link_order(self) -> LinkOrder {
+        // The IDs are only checked by the kernel when attaching, which fails
+        // if they don't name a program on the hook.
+        match self {
+            Self::First => LinkOrder::first(),
+            Self::Last => LinkOrder::last(),
+            Self::Before(id) => {
+                LinkOrder::before_program_id(unsafe { ProgramId::new(id) })
+            }
+            Self::After(id) => {
+                LinkOrder::after_program_id(unsafe { ProgramId::new(id) })
+            }
+        }
+    }
+}
+
+fn attach_tcx(
+    program: &mut SchedClassifier,
+    iface: &str,
+    attach_type: TcAttachType,
+    order: Order,
+) -> anyhow::Result<SchedClassifierLinkId> {
+    program
+        .attach_with_options(
+            iface,
+            attach_type,
+            TcAttachOptions::TcxOrder(order.link_order()),
+        )
+        .with_context(|| format!("failed to attach to {iface} with TCX"))
+}
+
+fn attach_clsact(
+    program: &mut SchedClassifier,
+    iface: &str,
+    attach_type: TcAttachType,
+    order: Order,
+) -> anyhow::Result<SchedClassifierLinkId> {
+    if !matches!(order, Order::Last) {
+        warn!("ordering only applies to TCX, ignoring it for {iface}");
+    }
+    clsact::add(iface)?;
+    program
+        .attach_with_options(
+            iface,
+            attach_type,
+            TcAttachOptions::Netlink(NlOptions::default()),
+        )
+        .with_context(|| format!("failed to attach to {iface} with clsact"))
+}
+
+/// Whether `error`, from creating a TCX link, means the kernel doesn't support
+/// TCX at all: kernels before 6.6 reject the attach type as invalid, and some
+/// devices don't implement it.
+fn tcx_unsupported(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::raw_os_error)
+            .is_some_and(|errno| {
+                errno == libc::EINVAL || errno == libc::EOPNOTSUPP
+            })
+    })
+}
+
+/// Attaches `program` to `iface` with `method`, returning the link and "TCX"
+/// or "clsact". In auto mode, if the kernel doesn't support TCX the program is
+/// attached with clsact instead, and the reason logged. Any other failure,
+/// including a `--before` or `--after` ID that isn't on the hook, is returned.
+pub fn attach(
+    program: &mut SchedClassifier,
+    iface: &str,
+    attach_type: TcAttachType,
+    method: AttachMethod,
+    order: Order,
+) -> anyhow::Result<(SchedClassifierLinkId, &'static str)> {
+    Ok(match method {
+        AttachMethod::Auto => {
+            match attach_tcx(program, iface, attach_type, order) {
+                Ok(link_id) => (link_id, "TCX"),
+                // With an ordering, EINVAL may just mean a bad program ID, and
+                // clsact couldn't honour the ordering anyway.
+                Err(e)
+                    if matches!(order, Order::Last) && tcx_unsupported(&e) =>
+                {
+                    warn!("{e:#}, falling back to clsact");
+                    let link_id =
+                        attach_clsact(program, iface, attach_type, order)?;
+                    (link_id, "clsact")
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        AttachMethod::Tcx => {
+            (attach_tcx(program, iface, attach_type, order)?, "TCX")
+        }
+        AttachMethod::Clsact => {
+            (attach_clsact(program, iface, attach_type, order)?, "clsact")
+        }
+    })
+}
+
+/// The programs attached to the TCX hook for `attach_type` on `iface`, in the
+/// order they run. Filters on the clsact qdisc aren't included.
+pub fn list(
+    iface: &str,
+    attach_type: TcAttachType,
+) -> anyhow::Result<Vec<ProgramInfo>> {
+    let (_revision, programs) = SchedClassifier::query_tcx(iface, attach_type)
+        .with_context(|| {
+            format!("failed to query the TCX programs on {iface}")
+        })?;
+    Ok(programs)
+}
+
+/// A program's ID and name, as `list` prints them.
+pub fn describe(program: &ProgramInfo) -> String {
+    format!(
+        "{} {}",
+        program.id(),
+        program.name_as_str().unwrap_or("(unnamed)")
+    )
+}
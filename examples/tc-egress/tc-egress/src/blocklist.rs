@@ -0,0 +1,37 @@
+//! The prefixes held in the classifiers' blocklists.
+
+use std::net::IpAddr;
+
+use aya::maps::{LpmTrie, MapData, MapError, lpm_trie::Key};
+use example_utils::prefix::Prefix;
+
+/// A pair of blocklist maps, one per address family: `BLOCKLIST` and
+/// `BLOCKLIST_V6`, or `INGRESS_BLOCKLIST` and `INGRESS_BLOCKLIST_V6`.
+pub struct Blocklist {
+    v4: LpmTrie<MapData, u32, u32>,
+    v6: LpmTrie<MapData, [u8; 16], u32>,
+}
+
+impl Blocklist {
+    pub fn new(
+        v4: LpmTrie<MapData, u32, u32>,
+        v6: LpmTrie<MapData, [u8; 16], u32>,
+    ) -> Self {
+        Self { v4, v6 }
+    }
+
+    pub fn insert(&mut self, prefix: Prefix) -> Result<(), MapError> {
+        let len = prefix.prefix_len().into();
+        match prefix.addr() {
+            // LPM trie keys are compared bit by bit from the most significant
+            // byte, so the address has to be stored in network byte order.
+            IpAddr::V4(addr) => {
+                self.v4
+                    .insert(&Key::new(len, u32::from(addr).to_be()), 0, 0)
+            }
+            IpAddr::V6(addr) => {
+                self.v6.insert(&Key::new(len, addr.octets()), 0, 0)
+            }
+        }
+    }
+}
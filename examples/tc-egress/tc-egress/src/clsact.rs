@@ -0,0 +1,80 @@
+//! The clsact qdisc the classifiers are attached to when TCX isn't available.
+//! Other programs may use it too, so it's only removed if this program added
+//! it, and only once no filters are left on it. A marker file records that it
+//! was added here, so that `cleanup` can still tell after a crash.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use aya::programs::tc;
+use log::info;
+
+use crate::netlink::{self, CLSACT_EGRESS, CLSACT_INGRESS};
+
+const STATE_DIR: &str = "/run/tc-egress";
+
+fn marker(iface: &str) -> PathBuf {
+    Path::new(STATE_DIR).join(format!("{iface}.clsact"))
+}
+
+/// Adds the clsact qdisc to `iface` unless it's already there, recording that
+/// it was added here.
+pub fn add(iface: &str) -> anyhow::Result<()> {
+    match tc::qdisc_add_clsact(iface) {
+        Ok(()) => {
+            fs::create_dir_all(STATE_DIR)
+                .and_then(|()| fs::write(marker(iface), ""))
+                .context("failed to record adding the clsact qdisc")?;
+            info!("added the clsact qdisc to {iface}");
+            Ok(())
+        }
+        // Added by another program, or by an earlier run that didn't remove it.
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(e).with_context(|| {
+            format!("failed to add the clsact qdisc to {iface}")
+        }),
+    }
+}
+
+/// Whether any filters are left on the clsact qdisc of `iface`.
+fn has_filters(ifindex: u32) -> io::Result<bool> {
+    for parent in [CLSACT_INGRESS, CLSACT_EGRESS] {
+        if !netlink::filters(ifindex, parent)?.is_empty() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Removes the clsact qdisc from `iface` if it was added here and no filters
+/// are left on it.
+pub fn remove_if_unused(iface: &str) -> anyhow::Result<()> {
+    if !marker(iface).exists() {
+        return Ok(());
+    }
+    let ifindex = netlink::ifindex(iface)
+        .with_context(|| format!("failed to look up {iface}"))?;
+    if has_filters(ifindex)
+        .with_context(|| format!("failed to list the filters on {iface}"))?
+    {
+        info!(
+            "leaving the clsact qdisc on {iface}, other filters still use it"
+        );
+        return Ok(());
+    }
+    netlink::delete_qdisc(
+        ifindex,
+        netlink::TC_H_CLSACT,
+        netlink::CLSACT_HANDLE,
+    )
+    .with_context(|| {
+        format!("failed to remove the clsact qdisc from {iface}")
+    })?;
+    fs::remove_file(marker(iface))
+        .context("failed to record removing the clsact qdisc")?;
+    info!("removed the clsact qdisc from {iface}");
+    Ok(())
+}
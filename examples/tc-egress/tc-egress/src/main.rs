@@ -1,18 +1,200 @@
-use std::net::Ipv4Addr;
+use std::{io, time::Duration};
 
+use anyhow::{Context as _, ensure};
 use aya::{
-    maps::HashMap,
+    maps::{LpmTrie, MapData, MapError, PerCpuArray},
     programs::{SchedClassifier, TcAttachType, tc},
 };
 use aya_log::EbpfLogger;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use example_utils::prefix::Prefix;
 use log::{info, warn};
-use tokio::signal;
+use tc_egress_common::{MAX_SHAPERS, Stats, direction};
+use tokio::{
+    signal,
+    time::{self, Instant},
+};
+
+mod attach;
+mod blocklist;
+mod clsact;
+mod netlink;
+mod shaping;
+
+use attach::{AttachMethod, Order};
+use blocklist::Blocklist;
+use shaping::{Shape, Shapers};
 
 #[derive(Debug, Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Opt {
     #[clap(short, long, default_value = "eth0")]
     iface: String,
+    /// Which traffic to classify.
+    #[clap(long, value_enum, default_value_t = Direction::Egress)]
+    direction: Direction,
+    /// How to attach the classifiers.
+    #[clap(long, value_enum, default_value_t = AttachMethod::Auto)]
+    attach_method: AttachMethod,
+    /// With TCX, run before the other programs on the hook instead of after
+    /// them.
+    #[clap(long, conflicts_with_all = ["before", "after"])]
+    first: bool,
+    /// With TCX, run just before the program with this ID, as printed by the
+    /// `list` subcommand.
+    #[clap(long, value_name = "ID", conflicts_with = "after")]
+    before: Option<u32>,
+    /// With TCX, run just after the program with this ID.
+    #[clap(long, value_name = "ID")]
+    after: Option<u32>,
+    /// Drop outgoing traffic to this prefix, e.g. 10.0.0.0/8 or
+    /// 2001:db8::/32. A bare address is treated as a single host. May be given
+    /// multiple times.
+    #[clap(short, long)]
+    block: Vec<Prefix>,
+    /// Drop incoming traffic from this prefix, in the same format as --block.
+    /// May be given multiple times.
+    #[clap(long)]
+    block_source: Vec<Prefix>,
+    /// Limit outgoing traffic to a prefix to a rate, e.g.
+    /// 10.1.0.0/16=100mbit, by holding packets back. Rates are in bit, kbit,
+    /// mbit or gbit per second. Makes fq the interface's root qdisc, or the
+    /// qdisc of each transmit queue, unless it has qdiscs set up by hand. May
+    /// be given multiple times.
+    #[clap(long, value_name = "PREFIX=RATE")]
+    shape: Vec<Shape>,
+    /// Seconds between packet counter summaries.
+    #[clap(
+        long,
+        default_value = "10",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    stats_interval: u64,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+impl Opt {
+    fn order(&self) -> Order {
+        match (self.first, self.before, self.after) {
+            (true, _, _) => Order::First,
+            (_, Some(id), _) => Order::Before(id),
+            (_, _, Some(id)) => Order::After(id),
+            _ => Order::Last,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the programs attached to an interface with TCX, in the order they
+    /// run, and exit. Filters on the clsact qdisc aren't included; `tc filter
+    /// show dev <IFACE> ingress` (or egress) lists those.
+    List {
+        #[clap(short, long, default_value = "eth0")]
+        iface: String,
+    },
+    /// Remove the clsact filters left on an interface by a run that crashed,
+    /// and the clsact qdisc if it was added by tc-egress and nothing else
+    /// uses it. Don't run it while tc-egress is running on the interface.
+    Cleanup {
+        #[clap(short, long, default_value = "eth0")]
+        iface: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Direction {
+    /// Drop incoming traffic by source address, see --block-source.
+    Ingress,
+    /// Drop outgoing traffic by destination address, see --block.
+    Egress,
+    Both,
+}
+
+/// A classifier in the eBPF object: its program name, where it's attached and
+/// its entry in `STATS`.
+struct Classifier {
+    program: &'static str,
+    attach_type: TcAttachType,
+    stats_index: u32,
+    name: &'static str,
+}
+
+const INGRESS: Classifier = Classifier {
+    program: "tc_ingress",
+    attach_type: TcAttachType::Ingress,
+    stats_index: direction::INGRESS,
+    name: "ingress",
+};
+
+const EGRESS: Classifier = Classifier {
+    program: "tc_egress",
+    attach_type: TcAttachType::Egress,
+    stats_index: direction::EGRESS,
+    name: "egress",
+};
+
+impl Direction {
+    fn classifiers(self) -> &'static [Classifier] {
+        match self {
+            Self::Ingress => &[INGRESS],
+            Self::Egress => &[EGRESS],
+            Self::Both => &[INGRESS, EGRESS],
+        }
+    }
+}
+
+/// Logs how many packets each of `classifiers` has passed and dropped.
+fn log_stats(
+    stats: &PerCpuArray<MapData, Stats>,
+    classifiers: &[Classifier],
+) -> Result<(), MapError> {
+    let mut summary = Vec::new();
+    for classifier in classifiers {
+        let mut total = Stats::default();
+        for cpu in stats.get(&classifier.stats_index, 0)?.iter() {
+            total.passed += cpu.passed;
+            total.dropped += cpu.dropped;
+        }
+        summary.push(format!(
+            "{}: passed {} packets, dropped {} packets",
+            classifier.name, total.passed, total.dropped
+        ));
+    }
+    info!("{}", summary.join("; "));
+    Ok(())
+}
+
+fn list(iface: &str) -> anyhow::Result<()> {
+    for classifier in Direction::Both.classifiers() {
+        println!("{iface} {}:", classifier.name);
+        for program in attach::list(iface, classifier.attach_type)? {
+            println!("  {}", attach::describe(&program));
+        }
+    }
+    Ok(())
+}
+
+fn cleanup(iface: &str) -> anyhow::Result<()> {
+    for classifier in Direction::Both.classifiers() {
+        let Classifier {
+            program,
+            attach_type,
+            name,
+            ..
+        } = classifier;
+        match tc::qdisc_detach_program(iface, *attach_type, program) {
+            Ok(()) => info!("removed {program} from {iface} {name}"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("failed to remove {program} from {iface} {name}")
+                });
+            }
+        }
+    }
+    clsact::remove_if_unused(iface)
 }
 
 #[tokio::main]
@@ -21,6 +203,31 @@ async fn main() -> Result<(), anyhow::Error> {
 
     env_logger::init();
 
+    match &opt.command {
+        Some(Command::List { iface }) => return list(iface),
+        Some(Command::Cleanup { iface }) => return cleanup(iface),
+        None => {}
+    }
+    ensure!(
+        opt.block.is_empty() || !matches!(opt.direction, Direction::Ingress),
+        "--block only applies to outgoing traffic, use --direction egress \
+         or both"
+    );
+    ensure!(
+        opt.block_source.is_empty()
+            || !matches!(opt.direction, Direction::Egress),
+        "--block-source only applies to incoming traffic, use --direction \
+         ingress or both"
+    );
+    ensure!(
+        opt.shape.is_empty() || !matches!(opt.direction, Direction::Ingress),
+        "--shape only applies to outgoing traffic"
+    );
+    ensure!(
+        opt.shape.len() <= MAX_SHAPERS as usize,
+        "at most {MAX_SHAPERS} prefixes can be shaped"
+    );
+
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
@@ -48,28 +255,111 @@ async fn main() -> Result<(), anyhow::Error> {
             });
         }
     }
-    // error adding clsact to the interface if it is already added is harmless
-    // the full cleanup can be done with 'sudo tc qdisc del dev eth0 clsact'.
-    let _ = tc::qdisc_add_clsact(&opt.iface);
-    let program: &mut SchedClassifier =
-        bpf.program_mut("tc_egress").unwrap().try_into()?;
-    program.load()?;
-    program.attach(&opt.iface, TcAttachType::Egress)?;
+    let classifiers = opt.direction.classifiers();
+    let mut links = Vec::new();
+    let mut used_clsact = false;
+    for classifier in classifiers {
+        // Querying fails on kernels without TCX, which have nothing to list.
+        if let Ok(programs) = attach::list(&opt.iface, classifier.attach_type)
+            && !programs.is_empty()
+        {
+            let programs: Vec<_> =
+                programs.iter().map(attach::describe).collect();
+            info!(
+                "already attached to {} {}: {}",
+                opt.iface,
+                classifier.name,
+                programs.join(", ")
+            );
+        }
+        let program: &mut SchedClassifier =
+            bpf.program_mut(classifier.program).unwrap().try_into()?;
+        program.load()?;
+        let (link_id, method) = attach::attach(
+            program,
+            &opt.iface,
+            classifier.attach_type,
+            opt.attach_method,
+            opt.order(),
+        )?;
+        info!(
+            "attached {} to {} {} with {method}",
+            classifier.program, opt.iface, classifier.name
+        );
+        links.push(link_id);
+        used_clsact |= method == "clsact";
+    }
 
     // (1)
-    let mut blocklist: HashMap<_, u32, u32> =
-        HashMap::try_from(bpf.map_mut("BLOCKLIST").unwrap())?;
+    let mut blocklist = Blocklist::new(
+        LpmTrie::try_from(bpf.take_map("BLOCKLIST").unwrap())?,
+        LpmTrie::try_from(bpf.take_map("BLOCKLIST_V6").unwrap())?,
+    );
 
     // (2)
-    let block_addr: u32 = Ipv4Addr::new(1, 1, 1, 1).into();
+    for &prefix in &opt.block {
+        // (3)
+        blocklist.insert(prefix)?;
+        info!("blocking traffic to {prefix}");
+    }
+
+    let mut ingress_blocklist = Blocklist::new(
+        LpmTrie::try_from(bpf.take_map("INGRESS_BLOCKLIST").unwrap())?,
+        LpmTrie::try_from(bpf.take_map("INGRESS_BLOCKLIST_V6").unwrap())?,
+    );
+    for &prefix in &opt.block_source {
+        ingress_blocklist.insert(prefix)?;
+        info!("blocking traffic from {prefix}");
+    }
+
+    // Install fq first, so that no packet is given a departure time before
+    // something holds it back until then. It's removed again when `fq` is
+    // dropped, including when anything below fails.
+    let fq = if opt.shape.is_empty() {
+        None
+    } else {
+        shaping::install_fq(&opt.iface)?
+    };
+    let mut shapers = Shapers::new(
+        LpmTrie::try_from(bpf.take_map("SHAPING").unwrap())?,
+        LpmTrie::try_from(bpf.take_map("SHAPING_V6").unwrap())?,
+    );
+    for (id, shape) in (0..).zip(&opt.shape) {
+        shapers.insert(id, shape)?;
+        info!("shaping traffic to {shape}");
+    }
 
-    // (3)
-    blocklist.insert(block_addr, 0, 0)?;
+    let stats: PerCpuArray<_, Stats> =
+        PerCpuArray::try_from(bpf.take_map("STATS").unwrap())?;
+    let period = Duration::from_secs(opt.stats_interval);
+    let mut interval = time::interval_at(Instant::now() + period, period);
 
     let ctrl_c = signal::ctrl_c();
+    tokio::pin!(ctrl_c);
     info!("Waiting for Ctrl-C...");
-    ctrl_c.await?;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => log_stats(&stats, classifiers)?,
+            res = &mut ctrl_c => {
+                res?;
+                break;
+            }
+        }
+    }
     info!("Exiting...");
+    // Detach before looking at the qdisc, so that our own filters don't count
+    // as users of it.
+    for (classifier, link_id) in classifiers.iter().zip(links) {
+        let program: &mut SchedClassifier =
+            bpf.program_mut(classifier.program).unwrap().try_into()?;
+        if let Err(e) = program.detach(link_id) {
+            warn!("failed to detach {}: {e}", classifier.program);
+        }
+    }
+    if used_clsact && let Err(e) = clsact::remove_if_unused(&opt.iface) {
+        warn!("{e:#}");
+    }
+    drop(fq);
 
     Ok(())
 }
@@ -0,0 +1,254 @@
+//! Just enough rtnetlink to manage the qdiscs tc-egress relies on, which is
+//! what the `tc` command would otherwise be run for. Talking to the kernel
+//! directly means iproute2 doesn't have to be installed, and nothing depends
+//! on the format of `tc`'s output.
+
+use std::{
+    ffi::CString,
+    io,
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+};
+
+/// The parent of the root qdisc.
+pub const TC_H_ROOT: u32 = 0xffff_ffff;
+/// The parent of the clsact qdisc.
+pub const TC_H_CLSACT: u32 = 0xffff_fff1;
+/// The handle of the clsact qdisc, `ffff:`.
+pub const CLSACT_HANDLE: u32 = 0xffff_0000;
+/// The ingress and egress hooks of the clsact qdisc, as filter parents.
+pub const CLSACT_INGRESS: u32 = 0xffff_fff2;
+pub const CLSACT_EGRESS: u32 = 0xffff_fff3;
+
+// From <linux/netlink.h> and <linux/rtnetlink.h>.
+const RTM_NEWQDISC: u16 = 36;
+const RTM_DELQDISC: u16 = 37;
+const RTM_GETQDISC: u16 = 38;
+const RTM_GETTCLASS: u16 = 42;
+const RTM_GETTFILTER: u16 = 46;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_DUMP: u16 = 0x300;
+const NLM_F_CREATE: u16 = 0x400;
+const TCA_KIND: u16 = 1;
+
+/// The lengths of `struct nlmsghdr` and `struct tcmsg`.
+const NLMSG_HDR_LEN: usize = 16;
+const TCMSG_LEN: usize = 20;
+
+/// A qdisc, class or filter, as the kernel lists it.
+pub struct TcObject {
+    /// What kind of qdisc, class or filter this is, e.g. `fq` or `bpf`. A
+    /// class has the kind of the qdisc it belongs to.
+    pub kind: String,
+    /// The major number in the upper 16 bits, the minor in the lower.
+    pub handle: u32,
+    pub parent: u32,
+}
+
+/// The index of the interface called `iface`.
+pub fn ifindex(iface: &str) -> io::Result<u32> {
+    let name = CString::new(iface)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+/// The qdiscs on interface `ifindex`.
+pub fn qdiscs(ifindex: u32) -> io::Result<Vec<TcObject>> {
+    dump(RTM_GETQDISC, ifindex, 0)
+}
+
+/// The classes on interface `ifindex`.
+pub fn classes(ifindex: u32) -> io::Result<Vec<TcObject>> {
+    dump(RTM_GETTCLASS, ifindex, 0)
+}
+
+/// The filters on interface `ifindex` under `parent`, e.g. [`CLSACT_EGRESS`].
+pub fn filters(ifindex: u32, parent: u32) -> io::Result<Vec<TcObject>> {
+    dump(RTM_GETTFILTER, ifindex, parent)
+}
+
+/// Puts a qdisc of `kind` with `handle` under `parent` on interface
+/// `ifindex`, in place of whatever qdisc was there.
+pub fn replace_qdisc(
+    ifindex: u32,
+    parent: u32,
+    handle: u32,
+    kind: &str,
+) -> io::Result<()> {
+    let flags = NLM_F_CREATE | NLM_F_REPLACE | NLM_F_ACK;
+    let mut request = message(RTM_NEWQDISC, flags, ifindex, handle, parent);
+    // A `TCA_KIND` attribute naming the qdisc, NUL-terminated and padded.
+    let len = 4 + kind.len() + 1;
+    request.extend_from_slice(&(len as u16).to_ne_bytes());
+    request.extend_from_slice(&TCA_KIND.to_ne_bytes());
+    request.extend_from_slice(kind.as_bytes());
+    request.resize(request.len() + len.next_multiple_of(4) - len + 1, 0);
+    let msg_len = request.len() as u32;
+    request[..4].copy_from_slice(&msg_len.to_ne_bytes());
+    transact(&request, |_| {})
+}
+
+/// Deletes the qdisc with `handle` under `parent` on interface `ifindex`.
+pub fn delete_qdisc(ifindex: u32, parent: u32, handle: u32) -> io::Result<()> {
+    let request = message(RTM_DELQDISC, NLM_F_ACK, ifindex, handle, parent);
+    transact(&request, |_| {})
+}
+
+/// Builds a request made of `struct nlmsghdr` and `struct tcmsg`.
+fn message(
+    msg_type: u16,
+    flags: u16,
+    ifindex: u32,
+    handle: u32,
+    parent: u32,
+) -> Vec<u8> {
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&((NLMSG_HDR_LEN + TCMSG_LEN) as u32).to_ne_bytes());
+    msg.extend_from_slice(&msg_type.to_ne_bytes());
+    msg.extend_from_slice(&(NLM_F_REQUEST | flags).to_ne_bytes());
+    msg.extend_from_slice(&[0; 8]); // sequence number, port ID
+    msg.extend_from_slice(&[libc::AF_UNSPEC as u8, 0, 0, 0]); // family, padding
+    msg.extend_from_slice(&ifindex.to_ne_bytes());
+    msg.extend_from_slice(&handle.to_ne_bytes());
+    msg.extend_from_slice(&parent.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes()); // info
+    msg
+}
+
+/// Lists the qdiscs, classes or filters of `msg_type` under `parent` on
+/// interface `ifindex`.
+fn dump(msg_type: u16, ifindex: u32, parent: u32) -> io::Result<Vec<TcObject>> {
+    let request = message(msg_type, NLM_F_DUMP, ifindex, 0, parent);
+    let mut objects = Vec::new();
+    transact(&request, |payload| {
+        // Some dumps cover every interface, whatever the request says.
+        if let Some((index, object)) = parse(payload)
+            && index == ifindex
+        {
+            objects.push(object);
+        }
+    })?;
+    Ok(objects)
+}
+
+/// Parses a `struct tcmsg` and its `TCA_KIND` attribute, returning the index
+/// of the interface along with the object.
+fn parse(payload: &[u8]) -> Option<(u32, TcObject)> {
+    let header = payload.get(..TCMSG_LEN)?;
+    let field = |offset: usize| {
+        u32::from_ne_bytes(header[offset..offset + 4].try_into().unwrap())
+    };
+    let mut kind = String::new();
+    let mut attrs = &payload[TCMSG_LEN..];
+    // Each attribute is `struct rtattr`, its length and type, followed by its
+    // payload, padded to 4 bytes.
+    while let [len_lo, len_hi, type_lo, type_hi, ..] = *attrs {
+        let len = usize::from(u16::from_ne_bytes([len_lo, len_hi]));
+        if len < 4 || len > attrs.len() {
+            break;
+        }
+        if u16::from_ne_bytes([type_lo, type_hi]) == TCA_KIND {
+            let value = &attrs[4..len];
+            let value = value.strip_suffix(&[0]).unwrap_or(value);
+            kind = String::from_utf8_lossy(value).into_owned();
+        }
+        attrs = &attrs[len.next_multiple_of(4).min(attrs.len())..];
+    }
+    Some((
+        field(4),
+        TcObject {
+            kind,
+            handle: field(8),
+            parent: field(12),
+        },
+    ))
+}
+
+/// Sends `request` to the kernel and passes the payload of each message in
+/// the reply to `on_message`, until the kernel acknowledges the request or
+/// finishes the dump.
+fn transact(
+    request: &[u8],
+    mut on_message: impl FnMut(&[u8]),
+) -> io::Result<()> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    // An unbound netlink socket sends to the kernel.
+    let sent = unsafe {
+        libc::send(
+            socket.as_raw_fd(),
+            request.as_ptr().cast(),
+            request.len(),
+            0,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        // With `MSG_TRUNC`, a reply too big for the buffer reports its full
+        // length rather than being cut short without notice.
+        let len = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                libc::MSG_TRUNC,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut rest = buf.get(..len as usize).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "netlink reply too long")
+        })?;
+        while rest.len() >= NLMSG_HDR_LEN {
+            let msg_len =
+                u32::from_ne_bytes(rest[..4].try_into().unwrap()) as usize;
+            let msg_type = u16::from_ne_bytes(rest[4..6].try_into().unwrap());
+            if !(NLMSG_HDR_LEN..=rest.len()).contains(&msg_len) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "malformed netlink message",
+                ));
+            }
+            let payload = &rest[NLMSG_HDR_LEN..msg_len];
+            match msg_type {
+                NLMSG_DONE => return Ok(()),
+                NLMSG_ERROR => {
+                    // `struct nlmsgerr` starts with a negated errno, or 0 when
+                    // acknowledging a request.
+                    let error = payload
+                        .get(..4)
+                        .map(|error| {
+                            i32::from_ne_bytes(error.try_into().unwrap())
+                        })
+                        .unwrap_or(-libc::EINVAL);
+                    return match error {
+                        0 => Ok(()),
+                        error => Err(io::Error::from_raw_os_error(-error)),
+                    };
+                }
+                _ => on_message(payload),
+            }
+            rest = &rest[msg_len.next_multiple_of(4).min(rest.len())..];
+        }
+    }
+}
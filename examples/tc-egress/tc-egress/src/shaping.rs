@@ -0,0 +1,200 @@
+//! Shaping outgoing traffic per destination prefix. `tc_egress` sets each
+//! packet's departure time, and the fq qdisc holds packets until then.
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use anyhow::{Context as _, anyhow, ensure};
+use aya::maps::{LpmTrie, MapData, MapError, lpm_trie::Key};
+use example_utils::prefix::Prefix;
+use log::{info, warn};
+use tc_egress_common::Shaper;
+
+use crate::netlink::{self, TC_H_ROOT, TcObject};
+
+/// A destination prefix and the rate traffic to it is limited to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Shape {
+    pub prefix: Prefix,
+    /// Bits per second.
+    pub rate: u64,
+}
+
+/// Parses a rate in bits per second with a unit, as `tc` does: `bit`, `kbit`,
+/// `mbit` or `gbit`, in powers of 1000.
+fn parse_rate(s: &str) -> anyhow::Result<u64> {
+    let (number, multiplier) = [
+        ("gbit", 1_000_000_000),
+        ("mbit", 1_000_000),
+        ("kbit", 1_000),
+        ("bit", 1),
+    ]
+    .into_iter()
+    .find_map(|(unit, multiplier)| {
+        s.strip_suffix(unit).map(|number| (number, multiplier))
+    })
+    .ok_or_else(|| anyhow!("rate {s} has no unit, e.g. 100mbit"))?;
+    let rate = number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| anyhow!("invalid rate {s}"))?;
+    // The classifiers count in bytes.
+    ensure!(rate >= 8, "rate {s} is less than a byte per second");
+    Ok(rate)
+}
+
+impl FromStr for Shape {
+    type Err = anyhow::Error;
+
+    /// Parses `prefix=rate`, e.g. `10.1.0.0/16=100mbit`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, rate) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected PREFIX=RATE, got {s}"))?;
+        Ok(Self {
+            prefix: prefix.parse()?,
+            rate: parse_rate(rate)?,
+        })
+    }
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {} bit/s", self.prefix, self.rate)
+    }
+}
+
+/// The `SHAPING` and `SHAPING_V6` maps.
+pub struct Shapers {
+    v4: LpmTrie<MapData, u32, Shaper>,
+    v6: LpmTrie<MapData, [u8; 16], Shaper>,
+}
+
+impl Shapers {
+    pub fn new(
+        v4: LpmTrie<MapData, u32, Shaper>,
+        v6: LpmTrie<MapData, [u8; 16], Shaper>,
+    ) -> Self {
+        Self { v4, v6 }
+    }
+
+    /// Shapes traffic to `shape.prefix`, keeping its departure times in entry
+    /// `id` of `DEPARTURES`.
+    pub fn insert(&mut self, id: u32, shape: &Shape) -> Result<(), MapError> {
+        let shaper = Shaper::new(id, shape.rate / 8);
+        let len = shape.prefix.prefix_len().into();
+        match shape.prefix.addr() {
+            IpAddr::V4(addr) => self.v4.insert(
+                &Key::new(len, u32::from(addr).to_be()),
+                shaper,
+                0,
+            ),
+            IpAddr::V6(addr) => {
+                self.v6.insert(&Key::new(len, addr.octets()), shaper, 0)
+            }
+        }
+    }
+}
+
+/// Whether the kernel set `qdisc` up by itself, rather than someone
+/// configuring it.
+fn is_default(qdisc: &TcObject) -> bool {
+    qdisc.handle == 0
+}
+
+/// fq installed by [`install_fq`], which is removed again when this is
+/// dropped, on the way out of an error as much as at the end of a run.
+pub struct Fq {
+    iface: String,
+    ifindex: u32,
+}
+
+impl Drop for Fq {
+    fn drop(&mut self) {
+        if let Err(e) = remove_fq(&self.iface, self.ifindex) {
+            warn!("{e:#}");
+        }
+    }
+}
+
+/// Makes fq the root qdisc of `iface`, or on a multiqueue interface the qdisc
+/// of each transmit queue, unless it already is. Returns `None` if it already
+/// was, so that it isn't removed afterwards.
+///
+/// Only qdiscs the kernel set up by itself are replaced, so that [`remove_fq`]
+/// can bring them back. Anything else is left alone, and an error returned.
+pub fn install_fq(iface: &str) -> anyhow::Result<Option<Fq>> {
+    let ifindex = netlink::ifindex(iface)
+        .with_context(|| format!("failed to look up {iface}"))?;
+    let qdiscs = netlink::qdiscs(ifindex)
+        .with_context(|| format!("failed to list the qdiscs on {iface}"))?;
+    let root = qdiscs
+        .iter()
+        .find(|qdisc| qdisc.parent == TC_H_ROOT)
+        .with_context(|| format!("{iface} has no root qdisc"))?;
+    // The children of an mq qdisc have its major number as the major number
+    // of their parent, e.g. `:1` under the default `0:`.
+    let children: Vec<_> = qdiscs
+        .iter()
+        .filter(|qdisc| {
+            qdisc.parent != TC_H_ROOT && qdisc.parent >> 16 == root.handle >> 16
+        })
+        .collect();
+    let multiqueue = root.kind == "mq";
+    let has_fq = if multiqueue {
+        !children.is_empty() && children.iter().all(|child| child.kind == "fq")
+    } else {
+        root.kind == "fq"
+    };
+    if has_fq {
+        return Ok(None);
+    }
+    ensure!(
+        is_default(root) && children.iter().all(|child| is_default(child)),
+        "{iface} has a {} root qdisc that was set up by hand, which shaping \
+         would replace; make it fq, or remove it",
+        root.kind
+    );
+
+    let installed = if multiqueue {
+        // Giving mq a handle of its own, `1:`, lets fq be put under its
+        // classes.
+        netlink::replace_qdisc(ifindex, TC_H_ROOT, 0x0001_0000, "mq").and_then(
+            |()| {
+                for class in netlink::classes(ifindex)? {
+                    if class.kind == "mq" {
+                        netlink::replace_qdisc(ifindex, class.handle, 0, "fq")?;
+                    }
+                }
+                Ok(())
+            },
+        )
+    } else {
+        netlink::replace_qdisc(ifindex, TC_H_ROOT, 0, "fq")
+    };
+    if let Err(e) = installed {
+        // Put back whatever default qdiscs were there.
+        let _ = remove_fq(iface, ifindex);
+        return Err(e)
+            .with_context(|| format!("failed to install fq on {iface}"));
+    }
+    if multiqueue {
+        info!("installed fq under each transmit queue of {iface}");
+    } else {
+        info!("installed fq as the root qdisc of {iface}");
+    }
+    Ok(Some(Fq {
+        iface: iface.to_owned(),
+        ifindex,
+    }))
+}
+
+/// Removes the fq qdiscs installed by [`install_fq`]. Deleting the root qdisc
+/// makes the kernel set up its defaults again, which are what `install_fq`
+/// replaced.
+fn remove_fq(iface: &str, ifindex: u32) -> anyhow::Result<()> {
+    netlink::delete_qdisc(ifindex, TC_H_ROOT, 0)
+        .with_context(|| format!("failed to remove fq from {iface}"))?;
+    info!("removed fq from {iface}");
+    Ok(())
+}
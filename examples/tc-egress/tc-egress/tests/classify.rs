@@ -0,0 +1,158 @@
+//! Tests for `tc_ingress` and `tc_egress`, checking the action each picks for
+//! hand-built frames and what they count.
+
+mod common;
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use aya::{
+    Ebpf,
+    maps::{LpmTrie, PerCpuArray, lpm_trie::Key},
+};
+use common::*;
+use tc_egress_common::{Stats, direction};
+
+const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const BLOCKED: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+const OTHER: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+fn block(bpf: &mut Ebpf, map: &str, addr: Ipv4Addr, len: u32) {
+    let mut blocklist: LpmTrie<_, u32, u32> =
+        LpmTrie::try_from(bpf.map_mut(map).unwrap()).unwrap();
+    blocklist
+        .insert(&Key::new(len, u32::from(addr).to_be()), 0, 0)
+        .unwrap();
+}
+
+fn block_v6(bpf: &mut Ebpf, map: &str, addr: Ipv6Addr, len: u32) {
+    let mut blocklist: LpmTrie<_, [u8; 16], u32> =
+        LpmTrie::try_from(bpf.map_mut(map).unwrap()).unwrap();
+    blocklist
+        .insert(&Key::new(len, addr.octets()), 0, 0)
+        .unwrap();
+}
+
+#[test]
+fn egress_drops_blocked_destinations() {
+    let mut bpf = load();
+    block(&mut bpf, "BLOCKLIST", BLOCKED, 32);
+
+    for (frame, action) in [
+        (ipv4_frame(LOCAL, BLOCKED), TC_ACT_SHOT),
+        (ipv4_frame(LOCAL, OTHER), TC_ACT_PIPE),
+        // The blocklist only applies to destinations.
+        (ipv4_frame(BLOCKED, LOCAL), TC_ACT_PIPE),
+        (arp_frame(), TC_ACT_PIPE),
+    ] {
+        assert_eq!(run(&bpf, "tc_egress", &frame), action, "{frame:02x?}");
+    }
+}
+
+#[test]
+fn ingress_drops_blocked_sources() {
+    let mut bpf = load();
+    block(&mut bpf, "INGRESS_BLOCKLIST", BLOCKED, 32);
+    // Blocking a destination on egress doesn't block it as a source.
+    block(&mut bpf, "BLOCKLIST", OTHER, 32);
+
+    for (frame, action) in [
+        (ipv4_frame(BLOCKED, LOCAL), TC_ACT_SHOT),
+        (ipv4_frame(OTHER, LOCAL), TC_ACT_PIPE),
+        (ipv4_frame(LOCAL, BLOCKED), TC_ACT_PIPE),
+        (arp_frame(), TC_ACT_PIPE),
+    ] {
+        assert_eq!(run(&bpf, "tc_ingress", &frame), action, "{frame:02x?}");
+    }
+}
+
+#[test]
+fn matches_prefixes() {
+    let mut bpf = load();
+    block(&mut bpf, "BLOCKLIST", Ipv4Addr::new(198, 51, 100, 0), 24);
+    block(
+        &mut bpf,
+        "INGRESS_BLOCKLIST",
+        Ipv4Addr::new(203, 0, 0, 0),
+        8,
+    );
+
+    for (name, frame, action) in [
+        ("tc_egress", ipv4_frame(LOCAL, BLOCKED), TC_ACT_SHOT),
+        (
+            "tc_egress",
+            ipv4_frame(LOCAL, Ipv4Addr::new(198, 51, 101, 7)),
+            TC_ACT_PIPE,
+        ),
+        ("tc_ingress", ipv4_frame(OTHER, LOCAL), TC_ACT_SHOT),
+        (
+            "tc_ingress",
+            ipv4_frame(Ipv4Addr::new(204, 0, 113, 7), LOCAL),
+            TC_ACT_PIPE,
+        ),
+    ] {
+        assert_eq!(run(&bpf, name, &frame), action, "{name} {frame:02x?}");
+    }
+}
+
+#[test]
+fn matches_ipv6_prefixes() {
+    let local: Ipv6Addr = "2001:db8::1".parse().unwrap();
+    let blocked: Ipv6Addr = "2001:db8:bad::7".parse().unwrap();
+    let other: Ipv6Addr = "2001:db8:600d::7".parse().unwrap();
+    let mut bpf = load();
+    block_v6(
+        &mut bpf,
+        "BLOCKLIST_V6",
+        "2001:db8:bad::".parse().unwrap(),
+        48,
+    );
+    block_v6(&mut bpf, "INGRESS_BLOCKLIST_V6", blocked, 128);
+    // IPv4 prefixes don't apply to IPv6 packets.
+    block(&mut bpf, "BLOCKLIST", Ipv4Addr::UNSPECIFIED, 0);
+
+    for (name, frame, action) in [
+        ("tc_egress", ipv6_frame(local, blocked), TC_ACT_SHOT),
+        ("tc_egress", ipv6_frame(local, other), TC_ACT_PIPE),
+        ("tc_ingress", ipv6_frame(blocked, local), TC_ACT_SHOT),
+        ("tc_ingress", ipv6_frame(other, local), TC_ACT_PIPE),
+    ] {
+        assert_eq!(run(&bpf, name, &frame), action, "{name} {frame:02x?}");
+    }
+}
+
+#[test]
+fn drops_truncated_headers() {
+    let bpf = load();
+    let local: Ipv6Addr = "2001:db8::1".parse().unwrap();
+    // Cut each frame in the middle of the IP header.
+    for frame in [ipv4_frame(LOCAL, OTHER), ipv6_frame(local, local)] {
+        for name in ["tc_ingress", "tc_egress"] {
+            assert_eq!(run(&bpf, name, &frame[..14 + 10]), TC_ACT_SHOT);
+        }
+    }
+}
+
+#[test]
+fn counts_each_direction() {
+    let mut bpf = load();
+    block(&mut bpf, "INGRESS_BLOCKLIST", BLOCKED, 32);
+    block(&mut bpf, "BLOCKLIST", BLOCKED, 32);
+
+    run(&bpf, "tc_ingress", &ipv4_frame(BLOCKED, LOCAL));
+    run(&bpf, "tc_ingress", &ipv4_frame(OTHER, LOCAL));
+    run(&bpf, "tc_ingress", &ipv4_frame(OTHER, LOCAL));
+    run(&bpf, "tc_egress", &ipv4_frame(LOCAL, BLOCKED));
+
+    let stats: PerCpuArray<_, Stats> =
+        PerCpuArray::try_from(bpf.map("STATS").unwrap()).unwrap();
+    let total = |index| {
+        stats.get(&index, 0).unwrap().iter().fold(
+            (0, 0),
+            |(passed, dropped), cpu| {
+                (passed + cpu.passed, dropped + cpu.dropped)
+            },
+        )
+    };
+    assert_eq!(total(direction::INGRESS), (2, 1));
+    assert_eq!(total(direction::EGRESS), (0, 1));
+}
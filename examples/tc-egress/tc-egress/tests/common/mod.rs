@@ -0,0 +1,118 @@
+//! Runs the classifiers over hand-built frames with the `BPF_PROG_TEST_RUN`
+//! harness in `example-test-utils`. Unlike the XDP examples, these tests pass
+//! in a `__sk_buff` too, so that the shaping tests can set and read back the
+//! packet's departure time.
+#![allow(dead_code)]
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use aya::{Ebpf, programs::SchedClassifier};
+use example_test_utils::test_run_with_ctx;
+
+pub const TC_ACT_SHOT: i32 = 2;
+pub const TC_ACT_PIPE: i32 = 3;
+
+/// The start of `struct __sk_buff`, up to and including `tstamp`, which is as
+/// much as the tests pass in and read back.
+#[repr(C)]
+struct SkBuff {
+    _fields: [u32; 36],
+    _flow_keys: u64,
+    tstamp: u64,
+}
+
+/// Loads the eBPF object and both classifiers in it.
+pub fn load() -> Ebpf {
+    let mut bpf = Ebpf::load(aya::include_bytes_aligned!(concat!(
+        env!("OUT_DIR"),
+        "/tc-egress"
+    )))
+    .unwrap();
+    for name in ["tc_ingress", "tc_egress"] {
+        let program: &mut SchedClassifier =
+            bpf.program_mut(name).unwrap().try_into().unwrap();
+        program.load().unwrap();
+    }
+    bpf
+}
+
+/// Runs the classifier called `name` once over `frame` and returns the action
+/// it chose.
+pub fn run(bpf: &Ebpf, name: &str, frame: &[u8]) -> i32 {
+    run_with_tstamp(bpf, name, frame, 0).0
+}
+
+/// Runs the classifier called `name` once over `frame`, a packet scheduled to
+/// leave at `tstamp`, and returns the action it chose and the packet's
+/// `tstamp` afterwards.
+pub fn run_with_tstamp(
+    bpf: &Ebpf,
+    name: &str,
+    frame: &[u8],
+    tstamp: u64,
+) -> (i32, u64) {
+    let program: &SchedClassifier =
+        bpf.program(name).unwrap().try_into().unwrap();
+    let mut skb = SkBuff {
+        _fields: [0; 36],
+        _flow_keys: 0,
+        tstamp,
+    };
+    let action = test_run_with_ctx(program.fd().unwrap(), frame, &mut skb);
+    (action as i32, skb.tstamp)
+}
+
+/// An empty UDP datagram from port 40000 to port 53.
+const UDP: [u8; 8] = [
+    0x9c, 0x40, 0, 53, // source port, destination port
+    0, 8, 0, 0, // length, checksum
+];
+
+/// Builds an Ethernet + IPv4 frame carrying an empty UDP datagram from `src`
+/// to `dst`.
+pub fn ipv4_frame(src: Ipv4Addr, dst: Ipv4Addr) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]); // destination MAC
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]); // source MAC
+    frame.extend_from_slice(&0x0800u16.to_be_bytes()); // EtherType::Ipv4
+    frame.extend_from_slice(&[0x45, 0]); // version, IHL, DSCP
+    frame.extend_from_slice(&(20 + UDP.len() as u16).to_be_bytes());
+    frame.extend_from_slice(&[
+        0, 0, 0, 0, // identification, flags, fragment offset
+        64, 17, 0, 0, // TTL, protocol (UDP), checksum
+    ]);
+    frame.extend_from_slice(&src.octets());
+    frame.extend_from_slice(&dst.octets());
+    frame.extend_from_slice(&UDP);
+    frame
+}
+
+/// Builds an Ethernet + IPv6 frame carrying an empty UDP datagram from `src`
+/// to `dst`.
+pub fn ipv6_frame(src: Ipv6Addr, dst: Ipv6Addr) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]); // destination MAC
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]); // source MAC
+    frame.extend_from_slice(&0x86ddu16.to_be_bytes()); // EtherType::Ipv6
+    frame.extend_from_slice(&[0x60, 0, 0, 0]); // version, class, flow label
+    frame.extend_from_slice(&(UDP.len() as u16).to_be_bytes());
+    frame.extend_from_slice(&[17, 64]); // next header (UDP), hop limit
+    frame.extend_from_slice(&src.octets());
+    frame.extend_from_slice(&dst.octets());
+    frame.extend_from_slice(&UDP);
+    frame
+}
+
+/// Builds an ARP request for 192.0.2.1.
+pub fn arp_frame() -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&[0xff; 6]); // destination MAC
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]); // source MAC
+    frame.extend_from_slice(&0x0806u16.to_be_bytes()); // EtherType::Arp
+    frame.extend_from_slice(&[
+        0, 1, 0x08, 0, 6, 4, 0, 1, // Ethernet, IPv4, request
+        0x02, 0, 0, 0, 0, 0x02, 192, 0, 2, 2, // sender
+        0, 0, 0, 0, 0, 0, 192, 0, 2, 1, // target
+    ]);
+    frame
+}
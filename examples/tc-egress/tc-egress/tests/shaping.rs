@@ -0,0 +1,111 @@
+//! Tests for shaping in `tc_egress`, checking the departure times it gives
+//! packets to shaped prefixes.
+
+mod common;
+
+use std::net::Ipv4Addr;
+
+use aya::{
+    Ebpf,
+    maps::{Array, LpmTrie, lpm_trie::Key},
+};
+use common::*;
+use tc_egress_common::Shaper;
+
+const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const SHAPED: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+const OTHER: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// Limits traffic to 198.51.100.0/24 to `rate` bytes per second.
+fn shape(bpf: &mut Ebpf, rate: u64) {
+    let mut shaping: LpmTrie<_, u32, Shaper> =
+        LpmTrie::try_from(bpf.map_mut("SHAPING").unwrap()).unwrap();
+    let prefix = u32::from(Ipv4Addr::new(198, 51, 100, 0)).to_be();
+    shaping
+        .insert(&Key::new(24, prefix), Shaper::new(3, rate), 0)
+        .unwrap();
+}
+
+fn departure(bpf: &Ebpf, id: u32) -> u64 {
+    let departures: Array<_, u64> =
+        Array::try_from(bpf.map("DEPARTURES").unwrap()).unwrap();
+    departures.get(&id, 0).unwrap()
+}
+
+#[test]
+fn spaces_packets_out_at_the_rate() {
+    let mut bpf = load();
+    // Slow enough that the test can't keep up, so every packet after the
+    // first is held back.
+    shape(&mut bpf, 100);
+    let frame = ipv4_frame(LOCAL, SHAPED);
+    let delay = frame.len() as u64 * NS_PER_SEC / 100;
+
+    // Nothing has been sent to the prefix, so the first packet leaves now.
+    assert_eq!(
+        run_with_tstamp(&bpf, "tc_egress", &frame, 0),
+        (TC_ACT_PIPE, 0)
+    );
+    let first = departure(&bpf, 3);
+    assert_ne!(first, 0);
+
+    let (action, second) = run_with_tstamp(&bpf, "tc_egress", &frame, 0);
+    assert_eq!((action, second), (TC_ACT_PIPE, first));
+    let (action, third) = run_with_tstamp(&bpf, "tc_egress", &frame, 0);
+    assert_eq!((action, third), (TC_ACT_PIPE, first + delay));
+    assert_eq!(departure(&bpf, 3), first + 2 * delay);
+}
+
+#[test]
+fn leaves_other_destinations_alone() {
+    let mut bpf = load();
+    shape(&mut bpf, 1000);
+
+    for _ in 0..3 {
+        let frame = ipv4_frame(LOCAL, OTHER);
+        assert_eq!(
+            run_with_tstamp(&bpf, "tc_egress", &frame, 0),
+            (TC_ACT_PIPE, 0)
+        );
+    }
+    // Shaping is only done on the way out.
+    for _ in 0..3 {
+        let frame = ipv4_frame(SHAPED, LOCAL);
+        assert_eq!(
+            run_with_tstamp(&bpf, "tc_ingress", &frame, 0),
+            (TC_ACT_PIPE, 0)
+        );
+    }
+    assert_eq!(departure(&bpf, 3), 0);
+}
+
+#[test]
+fn keeps_departure_times_already_set() {
+    let mut bpf = load();
+    shape(&mut bpf, 1000);
+    let frame = ipv4_frame(LOCAL, SHAPED);
+    // Far enough ahead of the clock not to be caught up with.
+    let tstamp = u64::MAX / 2;
+
+    assert_eq!(
+        run_with_tstamp(&bpf, "tc_egress", &frame, tstamp),
+        (TC_ACT_PIPE, tstamp)
+    );
+    assert_eq!(
+        departure(&bpf, 3),
+        tstamp + frame.len() as u64 * NS_PER_SEC / 1000
+    );
+}
+
+#[test]
+fn drops_packets_beyond_the_horizon() {
+    let mut bpf = load();
+    // Each packet takes several seconds at this rate.
+    shape(&mut bpf, 10);
+    let frame = ipv4_frame(LOCAL, SHAPED);
+
+    assert_eq!(run(&bpf, "tc_egress", &frame), TC_ACT_PIPE);
+    assert_eq!(run(&bpf, "tc_egress", &frame), TC_ACT_SHOT);
+}
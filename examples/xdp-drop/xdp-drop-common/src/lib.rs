@@ -1,11 +1,147 @@
 #![no_std]
 
+/// A dropped packet, sent to user space through the `EVENTS` ring buffer.
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct PacketLog {
-    pub ipv4_address: u32,
+    /// Source address, IPv4 addresses being IPv4-mapped.
+    pub address: [u8; 16],
     pub action: u32,
+    /// Which check dropped the packet, one of the [`reason`] constants.
+    pub reason: u32,
+}
+
+/// Values of [`PacketLog::reason`].
+pub mod reason {
+    /// The source matched `BLOCKLIST` or `BLOCKLIST_V6`.
+    pub const BLOCKLIST: u32 = 0;
+    /// The packet matched an entry of `RULES`.
+    pub const RULE: u32 = 1;
+    /// The source exceeded its rate limit.
+    pub const RATE_LIMIT: u32 = 2;
+    /// The firewall is in allow mode and the source isn't in `ALLOWLIST` or
+    /// `ALLOWLIST_V6`.
+    pub const NOT_ALLOWED: u32 = 3;
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for PacketLog {}
+
+/// Number of entries in the `STATS` map. It is indexed by the `xdp_action`
+/// returned for the packet: `XDP_ABORTED` (0), `XDP_DROP` (1) or `XDP_PASS` (2).
+pub const STATS_ENTRIES: u32 = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Stats {}
+
+/// Key of the `RULES` LPM trie, which drops traffic by source prefix, L4
+/// protocol and destination port.
+///
+/// An LPM trie matches keys bit by bit in memory order, so the protocol and
+/// port come first and are always matched exactly (they're covered by
+/// [`RuleKey::FIXED_BITS`]), followed by the source address which is matched by
+/// prefix. IPv4 addresses are stored IPv4-mapped (`::ffff:a.b.c.d`) so that
+/// both address families share the map.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RuleKey {
+    pub proto: u8,
+    _pad: u8,
+    /// Destination port in network byte order, or 0 to match any port.
+    pub port: [u8; 2],
+    pub addr: [u8; 16],
+}
+
+impl RuleKey {
+    /// Number of leading key bits that precede the address.
+    pub const FIXED_BITS: u32 = 32;
+
+    pub fn new(proto: u8, port: u16, addr: [u8; 16]) -> Self {
+        Self {
+            proto,
+            _pad: 0,
+            port: port.to_be_bytes(),
+            addr,
+        }
+    }
+}
+
+/// Maps an IPv4 address into the IPv6 address space, as `::ffff:a.b.c.d`.
+pub fn ipv4_mapped([a, b, c, d]: [u8; 4]) -> [u8; 16] {
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d]
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for RuleKey {}
+
+/// The single entry of the `RATE_LIMIT` map, configuring a token bucket per
+/// source address.
+///
+/// Tokens are nanoseconds of credit: a bucket gains one token per nanosecond
+/// and each packet costs `cost` tokens, so a rate of `pps` packets per second
+/// has a `cost` of `1_000_000_000 / pps`. A bucket holds at most `capacity`
+/// tokens, i.e. `burst * cost`. A `cost` of 0 disables rate limiting.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    pub cost: u64,
+    pub capacity: u64,
+}
+
+/// The token bucket of a source address, in the `BUCKETS` map.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Bucket {
+    pub tokens: u64,
+    /// `bpf_ktime_get_ns` when the bucket was last refilled.
+    pub updated: u64,
+    /// Number of packets dropped for exceeding the limit.
+    pub throttled: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for RateLimit {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Bucket {}
+
+/// The single entry of the `POLICY` map, which decides what happens to traffic
+/// that isn't blocked.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Policy {
+    /// One of the [`mode`] constants.
+    pub mode: u32,
+    /// The [`exception`]s let through in allow mode, or'ed together.
+    pub exceptions: u32,
+}
+
+/// Values of [`Policy::mode`].
+pub mod mode {
+    /// Pass everything that isn't blocked.
+    pub const BLOCK: u32 = 0;
+    /// Drop everything whose source isn't in `ALLOWLIST` or `ALLOWLIST_V6`.
+    pub const ALLOW: u32 = 1;
+}
+
+/// Traffic that [`Policy::exceptions`] can let through in allow mode
+/// regardless of its source.
+pub mod exception {
+    /// ARP, and the ICMPv6 neighbor solicitations and advertisements that
+    /// replace it in IPv6, without which the host can't resolve its neighbors.
+    pub const NEIGHBOR_DISCOVERY: u32 = 1 << 0;
+    /// TCP segments with the ACK flag set, i.e. anything but the first packet
+    /// of a connection. This is a stateless check: it lets replies to our own
+    /// connections in, but also unsolicited ACKs.
+    pub const ESTABLISHED: u32 = 1 << 1;
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Policy {}
@@ -3,17 +3,24 @@
 #![allow(nonstandard_style, dead_code)]
 
 use aya_ebpf::{
-    bindings::xdp_action,
+    bindings::{BPF_F_NO_PREALLOC, xdp_action},
+    helpers::bpf_ktime_get_ns,
     macros::{map, xdp},
-    maps::HashMap,
+    maps::{Array, LpmTrie, LruHashMap, PerCpuArray, RingBuf, lpm_trie::Key},
     programs::XdpContext,
 };
-use aya_log_ebpf::info;
+use aya_log_ebpf::debug;
 
 use core::mem;
 use network_types::{
     eth::{EthHdr, EtherType},
-    ip::Ipv4Hdr,
+    ip::{IpError, IpProto, Ipv4Hdr, Ipv6Hdr},
+    tcp::TcpHdr,
+    udp::UdpHdr,
+};
+use xdp_drop_common::{
+    Bucket, PacketLog, Policy, RateLimit, RuleKey, STATS_ENTRIES, Stats,
+    exception, ipv4_mapped, mode, reason,
 };
 
 #[cfg(not(test))]
@@ -23,14 +30,63 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
 }
 
 #[map] // (1)
-static BLOCKLIST: HashMap<u32, u32> =
-    HashMap::<u32, u32>::with_max_entries(1024, 0);
+static BLOCKLIST: LpmTrie<u32, u32> =
+    LpmTrie::<u32, u32>::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+#[map]
+static BLOCKLIST_V6: LpmTrie<[u8; 16], u32> =
+    LpmTrie::<[u8; 16], u32>::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+#[map]
+static ALLOWLIST: LpmTrie<u32, u32> =
+    LpmTrie::<u32, u32>::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+#[map]
+static ALLOWLIST_V6: LpmTrie<[u8; 16], u32> =
+    LpmTrie::<[u8; 16], u32>::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+#[map]
+static POLICY: Array<Policy> = Array::with_max_entries(1, 0);
+
+#[map]
+static RULES: LpmTrie<RuleKey, u32> =
+    LpmTrie::<RuleKey, u32>::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+#[map]
+static RATE_LIMIT: Array<RateLimit> = Array::with_max_entries(1, 0);
+
+/// Token buckets keyed on source address, IPv4 addresses being IPv4-mapped.
+/// Least recently seen sources are evicted when the map is full.
+#[map]
+static BUCKETS: LruHashMap<[u8; 16], Bucket> =
+    LruHashMap::with_max_entries(65536, 0);
+
+#[map]
+static STATS: PerCpuArray<Stats> =
+    PerCpuArray::with_max_entries(STATS_ENTRIES, 0);
+
+/// A `PacketLog` for every dropped packet. When user space falls behind and the
+/// buffer fills up, further events are lost but the packets are still dropped.
+#[map]
+static EVENTS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
 
 #[xdp]
 pub fn xdp_firewall(ctx: XdpContext) -> u32 {
-    match try_xdp_firewall(ctx) {
+    let len = ctx.data_end() - ctx.data();
+    let action = match try_xdp_firewall(ctx) {
         Ok(ret) => ret,
         Err(_) => xdp_action::XDP_ABORTED,
+    };
+    count(action, len);
+    action
+}
+
+fn count(action: u32, len: usize) {
+    if let Some(stats) = STATS.get_ptr_mut(action) {
+        // The map is per-CPU, so nothing else is updating this entry.
+        let stats = unsafe { &mut *stats };
+        stats.packets += 1;
+        stats.bytes += len as u64;
     }
 }
 
@@ -49,26 +105,365 @@ unsafe fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
 
 // (2)
 fn block_ip(address: u32) -> bool {
-    unsafe { BLOCKLIST.get(&address).is_some() }
+    // LPM trie keys are compared bit by bit from the most significant byte, so
+    // the address has to be stored in network byte order.
+    BLOCKLIST.get(&Key::new(32, address.to_be())).is_some()
 }
 
-fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
-    let ethhdr: *const EthHdr = unsafe { ptr_at(&ctx, 0)? };
-    match unsafe { (*ethhdr).ether_type() } {
-        Ok(EtherType::Ipv4) => {}
-        _ => return Ok(xdp_action::XDP_PASS),
+fn block_ipv6(address: [u8; 16]) -> bool {
+    BLOCKLIST_V6.get(&Key::new(128, address)).is_some()
+}
+
+fn allow_ip(address: u32) -> bool {
+    ALLOWLIST.get(&Key::new(32, address.to_be())).is_some()
+}
+
+fn allow_ipv6(address: [u8; 16]) -> bool {
+    ALLOWLIST_V6.get(&Key::new(128, address)).is_some()
+}
+
+/// Whether a packet gets past the allowlist: always in block mode, and in
+/// allow mode if it falls under one of the enabled `exceptions` or its source
+/// is `listed`.
+fn allowed(listed: impl FnOnce() -> bool, exceptions: u32) -> bool {
+    let Some(policy) = POLICY.get(0) else {
+        return true;
+    };
+    policy.mode != mode::ALLOW
+        || policy.exceptions & exceptions != 0
+        || listed()
+}
+
+/// The IHL field, the low nibble of the first byte of the IPv4 header, is the
+/// header length in 32-bit words. It's at least 5 and at most 15, i.e. up to 40
+/// bytes of options.
+const IPV4_IHL_MASK: u8 = 0x0f;
+const IPV4_MAX_LEN: usize = 60;
+/// The flags and fragment offset share the 7th and 8th bytes of the header.
+const IPV4_FRAG_OFFSET: usize = 6;
+const IPV4_FRAG_OFFSET_MASK: u16 = 0x1fff;
+
+/// Reads the length of the IPv4 header at `offset`, options included.
+fn ipv4_header_len(ctx: &XdpContext, offset: usize) -> Result<usize, ()> {
+    let vihl: *const u8 = unsafe { ptr_at(ctx, offset)? };
+    let len = usize::from(unsafe { *vihl } & IPV4_IHL_MASK) * 4;
+    // Malformed headers are rejected. This also tells the verifier that the
+    // transport header offset is bounded.
+    if !(Ipv4Hdr::LEN..=IPV4_MAX_LEN).contains(&len) {
+        return Err(());
     }
+    Ok(len)
+}
+
+/// Whether the IPv4 header at `offset` is that of a fragment other than the
+/// first, which carries payload where the transport header would be.
+fn later_fragment(ctx: &XdpContext, offset: usize) -> Result<bool, ()> {
+    let field: *const [u8; 2] =
+        unsafe { ptr_at(ctx, offset + IPV4_FRAG_OFFSET)? };
+    Ok(u16::from_be_bytes(unsafe { *field }) & IPV4_FRAG_OFFSET_MASK != 0)
+}
+
+// IPv6 extension headers that may precede the transport header.
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_DESTINATION_OPTIONS: u8 = 60;
 
-    let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
-    let source = u32::from_be_bytes(unsafe { (*ipv4hdr).src_addr });
+/// How many extension headers we walk before giving up. Each type should occur
+/// at most once, except destination options which may occur twice.
+const MAX_IPV6_EXT_HEADERS: usize = 8;
 
-    // (3)
-    let action = if block_ip(source) {
-        xdp_action::XDP_DROP
+/// The start of a hop-by-hop, routing or destination options header. `len` is
+/// the length of the header in 8-byte units, not counting the first 8 bytes.
+#[repr(C)]
+struct Ipv6ExtHdr {
+    next_hdr: u8,
+    len: u8,
+}
+
+#[repr(C)]
+struct Ipv6FragHdr {
+    next_hdr: u8,
+    _reserved: u8,
+    /// The fragment offset in 8-byte units, in the top 13 bits, and the "more
+    /// fragments" flag in the lowest bit.
+    offset_flags: [u8; 2],
+    _identification: [u8; 4],
+}
+
+const IPV6_FRAG_OFFSET_MASK: u16 = 0xfff8;
+
+/// Walks the extension headers after the IPv6 header at `offset`, returning
+/// the next header after them, its offset, and whether the packet is a
+/// fragment other than the first.
+///
+/// A packet with more than `MAX_IPV6_EXT_HEADERS` extension headers is checked
+/// as if the next header it didn't get to were its protocol.
+fn ipv6_payload(
+    ctx: &XdpContext,
+    offset: usize,
+) -> Result<(u8, usize, bool), ()> {
+    let ipv6hdr: *const Ipv6Hdr = unsafe { ptr_at(ctx, offset)? };
+    let mut next_hdr = proto_number(unsafe { (*ipv6hdr).next_hdr() });
+    let mut offset = offset + Ipv6Hdr::LEN;
+    // The verifier only accepts loops it can prove terminate, hence the fixed
+    // number of iterations.
+    for _ in 0..MAX_IPV6_EXT_HEADERS {
+        match next_hdr {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION_OPTIONS => {
+                let hdr: *const Ipv6ExtHdr = unsafe { ptr_at(ctx, offset)? };
+                next_hdr = unsafe { (*hdr).next_hdr };
+                offset += (usize::from(unsafe { (*hdr).len }) + 1) * 8;
+            }
+            IPV6_FRAGMENT => {
+                let hdr: *const Ipv6FragHdr = unsafe { ptr_at(ctx, offset)? };
+                let field = u16::from_be_bytes(unsafe { (*hdr).offset_flags });
+                next_hdr = unsafe { (*hdr).next_hdr };
+                offset += mem::size_of::<Ipv6FragHdr>();
+                if field & IPV6_FRAG_OFFSET_MASK != 0 {
+                    // The rest of the headers are in the first fragment.
+                    return Ok((next_hdr, offset, true));
+                }
+            }
+            _ => break,
+        }
+    }
+    Ok((next_hdr, offset, false))
+}
+
+/// The protocol number of a `proto()` or `next_hdr()` header field, including
+/// numbers that `IpProto` doesn't know about.
+fn proto_number(proto: Result<IpProto, IpError>) -> u8 {
+    match proto {
+        Ok(proto) => proto as u8,
+        Err(IpError::InvalidProto(proto)) => proto,
+    }
+}
+
+const TCP: u8 = IpProto::Tcp as u8;
+const UDP: u8 = IpProto::Udp as u8;
+const ICMPV6: u8 = IpProto::Ipv6Icmp as u8;
+
+/// The fields of a TCP or UDP header that the checks look at.
+#[derive(Clone, Copy)]
+struct Transport {
+    proto: u8,
+    dst_port: u16,
+    /// Whether this is a TCP segment with the ACK flag set.
+    ack: bool,
+}
+
+/// TCP flags are the 14th byte of the header.
+const TCP_FLAGS_OFFSET: usize = 13;
+const TCP_ACK: u8 = 0x10;
+
+/// Parses the TCP or UDP header starting at `offset`.
+///
+/// Fragments other than the first carry payload where that header would be,
+/// so only their protocol is known. They're given port 0, so that rules on the
+/// protocol alone still match them but rules on a port don't.
+fn transport(
+    ctx: &XdpContext,
+    proto: u8,
+    offset: usize,
+    later_fragment: bool,
+) -> Result<Option<Transport>, ()> {
+    match proto {
+        TCP | UDP if later_fragment => Ok(Some(Transport {
+            proto,
+            dst_port: 0,
+            ack: false,
+        })),
+        TCP => {
+            let tcphdr: *const TcpHdr = unsafe { ptr_at(ctx, offset)? };
+            let flags: *const u8 =
+                unsafe { ptr_at(ctx, offset + TCP_FLAGS_OFFSET)? };
+            Ok(Some(Transport {
+                proto,
+                dst_port: u16::from_be_bytes(unsafe { (*tcphdr).dest }),
+                ack: unsafe { *flags } & TCP_ACK != 0,
+            }))
+        }
+        UDP => {
+            let udphdr: *const UdpHdr = unsafe { ptr_at(ctx, offset)? };
+            Ok(Some(Transport {
+                proto,
+                dst_port: unsafe { (*udphdr).dst_port() },
+                ack: false,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+/// Whether the ICMPv6 message starting at `offset` is part of neighbor
+/// discovery.
+fn neighbor_discovery(ctx: &XdpContext, offset: usize) -> Result<bool, ()> {
+    let icmp_type: *const u8 = unsafe { ptr_at(ctx, offset)? };
+    Ok(matches!(
+        unsafe { *icmp_type },
+        ICMPV6_NEIGHBOR_SOLICITATION | ICMPV6_NEIGHBOR_ADVERTISEMENT
+    ))
+}
+
+/// The `exceptions` a TCP or UDP packet falls under.
+fn exceptions(transport: Option<Transport>) -> u32 {
+    match transport {
+        Some(Transport { ack: true, .. }) => exception::ESTABLISHED,
+        _ => 0,
+    }
+}
+
+/// Checks `RULES` for a rule on this destination port, or on any port.
+fn block_port(transport: Option<Transport>, source: [u8; 16]) -> bool {
+    let Some(Transport {
+        proto,
+        dst_port: port,
+        ..
+    }) = transport
+    else {
+        return false;
+    };
+    let prefix_len = RuleKey::FIXED_BITS + 128;
+    RULES
+        .get(&Key::new(prefix_len, RuleKey::new(proto, port, source)))
+        .is_some()
+        || RULES
+            .get(&Key::new(prefix_len, RuleKey::new(proto, 0, source)))
+            .is_some()
+}
+
+/// Takes a packet's worth of tokens from the bucket of `source`, returning
+/// whether it had run out.
+///
+/// Buckets are shared between CPUs and updated without locking, so concurrent
+/// packets from the same source may occasionally be miscounted. That's fine for
+/// a rate limit.
+fn rate_limited(source: [u8; 16]) -> bool {
+    let Some(&RateLimit { cost, capacity }) = RATE_LIMIT.get(0) else {
+        return false;
+    };
+    if cost == 0 {
+        return false;
+    }
+    let now = unsafe { bpf_ktime_get_ns() };
+    let Some(bucket) = BUCKETS.get_ptr_mut(&source) else {
+        let bucket = Bucket {
+            tokens: capacity - cost,
+            updated: now,
+            throttled: 0,
+        };
+        let _ = BUCKETS.insert(&source, &bucket, 0);
+        return false;
+    };
+    let bucket = unsafe { &mut *bucket };
+    let elapsed = now.saturating_sub(bucket.updated);
+    let tokens = bucket.tokens.saturating_add(elapsed).min(capacity);
+    bucket.updated = now;
+    if tokens < cost {
+        bucket.tokens = tokens;
+        bucket.throttled += 1;
+        true
+    } else {
+        bucket.tokens = tokens - cost;
+        false
+    }
+}
+
+/// Runs the remaining checks on a packet from `source`, reporting it to user
+/// space if it's dropped. `allowed` and `blocklisted` are the results of the
+/// allowlist and blocklist lookups, which depend on the address family.
+fn verdict(
+    allowed: bool,
+    blocklisted: bool,
+    transport: Option<Transport>,
+    source: [u8; 16],
+) -> u32 {
+    let reason = if !allowed {
+        reason::NOT_ALLOWED
+    } else if blocklisted {
+        reason::BLOCKLIST
+    } else if block_port(transport, source) {
+        reason::RULE
+    } else if rate_limited(source) {
+        reason::RATE_LIMIT
     } else {
-        xdp_action::XDP_PASS
+        return xdp_action::XDP_PASS;
+    };
+    let event = PacketLog {
+        address: source,
+        action: xdp_action::XDP_DROP,
+        reason,
+    };
+    let _ = EVENTS.output(&event, 0);
+    xdp_action::XDP_DROP
+}
+
+fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
+    let ethhdr: *const EthHdr = unsafe { ptr_at(&ctx, 0)? };
+    let action = match unsafe { (*ethhdr).ether_type() } {
+        Ok(EtherType::Ipv4) => {
+            let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
+            let source = u32::from_be_bytes(unsafe { (*ipv4hdr).src_addr });
+            let proto = proto_number(unsafe { (*ipv4hdr).proto() });
+            let offset = EthHdr::LEN + ipv4_header_len(&ctx, EthHdr::LEN)?;
+            let later_fragment = later_fragment(&ctx, EthHdr::LEN)?;
+            let transport = transport(&ctx, proto, offset, later_fragment)?;
+
+            let mapped = ipv4_mapped(source.to_be_bytes());
+
+            // (3)
+            let action = verdict(
+                allowed(|| allow_ip(source), exceptions(transport)),
+                block_ip(source),
+                transport,
+                mapped,
+            );
+            debug!(&ctx, "SRC: {:i}, ACTION: {}", source, action);
+            action
+        }
+        Ok(EtherType::Ipv6) => {
+            let ipv6hdr: *const Ipv6Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
+            let source = unsafe { (*ipv6hdr).src_addr };
+            let (proto, offset, later_fragment) =
+                ipv6_payload(&ctx, EthHdr::LEN)?;
+            let neighbor_discovery = match proto {
+                ICMPV6 if !later_fragment => neighbor_discovery(&ctx, offset)?,
+                _ => false,
+            };
+            let transport = transport(&ctx, proto, offset, later_fragment)?;
+            let exceptions = if neighbor_discovery {
+                exception::NEIGHBOR_DISCOVERY
+            } else {
+                exceptions(transport)
+            };
+
+            let action = verdict(
+                allowed(|| allow_ipv6(source), exceptions),
+                block_ipv6(source),
+                transport,
+                source,
+            );
+            debug!(&ctx, "SRC: {:i}, ACTION: {}", source, action);
+            action
+        }
+        // Anything else has no source address to check, so in allow mode it
+        // is only let through if it's ARP and that exception is enabled.
+        other => {
+            let exceptions = match other {
+                Ok(EtherType::Arp) => exception::NEIGHBOR_DISCOVERY,
+                _ => 0,
+            };
+            if allowed(|| false, exceptions) {
+                xdp_action::XDP_PASS
+            } else {
+                xdp_action::XDP_DROP
+            }
+        }
     };
-    info!(&ctx, "SRC: {:i}, ACTION: {}", source, action);
 
     Ok(action)
 }
@@ -0,0 +1,140 @@
+//! The prefixes held in the firewall's `BLOCKLIST` and `BLOCKLIST_V6` maps.
+
+use std::{
+    borrow::{Borrow, BorrowMut},
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use anyhow::Context as _;
+use aya::maps::{LpmTrie, Map, MapData, MapError, lpm_trie::Key};
+use example_utils::prefix::Prefix;
+
+/// Somewhere to keep a set of prefixes.
+///
+/// [`Blocklist`] implements this on top of the firewall's maps; the trait
+/// exists so that [`sync`] can be exercised without loading any eBPF.
+pub trait PrefixMap {
+    fn prefixes(&self) -> Result<BTreeSet<Prefix>, MapError>;
+    fn insert(&mut self, prefix: Prefix) -> Result<(), MapError>;
+    fn remove(&mut self, prefix: Prefix) -> Result<(), MapError>;
+}
+
+/// The `BLOCKLIST` and `BLOCKLIST_V6` maps, or the `ALLOWLIST` and
+/// `ALLOWLIST_V6` maps which have the same layout.
+pub struct Blocklist<T> {
+    v4: LpmTrie<T, u32, u32>,
+    v6: LpmTrie<T, [u8; 16], u32>,
+}
+
+impl<T: Borrow<MapData>> Blocklist<T> {
+    pub fn new(
+        v4: LpmTrie<T, u32, u32>,
+        v6: LpmTrie<T, [u8; 16], u32>,
+    ) -> Self {
+        Self { v4, v6 }
+    }
+}
+
+impl Blocklist<MapData> {
+    /// Opens the maps pinned in `dir` by `xdp-drop --detach`.
+    pub fn from_pin(dir: &Path) -> Result<Self, MapError> {
+        let open = |name| MapData::from_pin(dir.join(name)).map(Map::LpmTrie);
+        Ok(Self::new(
+            open("BLOCKLIST")?.try_into()?,
+            open("BLOCKLIST_V6")?.try_into()?,
+        ))
+    }
+}
+
+impl<T: BorrowMut<MapData>> PrefixMap for Blocklist<T> {
+    fn prefixes(&self) -> Result<BTreeSet<Prefix>, MapError> {
+        let mut prefixes = BTreeSet::new();
+        for key in self.v4.keys() {
+            let key = key?;
+            let addr = Ipv4Addr::from(u32::from_be(key.data())).into();
+            prefixes.insert(
+                Prefix::new(addr, key.prefix_len() as u8)
+                    .expect("the kernel checks prefix lengths"),
+            );
+        }
+        for key in self.v6.keys() {
+            let key = key?;
+            let addr = Ipv6Addr::from(key.data()).into();
+            prefixes.insert(
+                Prefix::new(addr, key.prefix_len() as u8)
+                    .expect("the kernel checks prefix lengths"),
+            );
+        }
+        Ok(prefixes)
+    }
+
+    fn insert(&mut self, prefix: Prefix) -> Result<(), MapError> {
+        let len = prefix.prefix_len().into();
+        match prefix.addr() {
+            IpAddr::V4(addr) => {
+                self.v4
+                    .insert(&Key::new(len, u32::from(addr).to_be()), 0, 0)
+            }
+            IpAddr::V6(addr) => {
+                self.v6.insert(&Key::new(len, addr.octets()), 0, 0)
+            }
+        }
+    }
+
+    fn remove(&mut self, prefix: Prefix) -> Result<(), MapError> {
+        let len = prefix.prefix_len().into();
+        match prefix.addr() {
+            IpAddr::V4(addr) => {
+                self.v4.remove(&Key::new(len, u32::from(addr).to_be()))
+            }
+            IpAddr::V6(addr) => self.v6.remove(&Key::new(len, addr.octets())),
+        }
+    }
+}
+
+/// Parses a blocklist file: one prefix per line, ignoring blank lines and
+/// anything after a `#`.
+pub fn parse(contents: &str) -> anyhow::Result<BTreeSet<Prefix>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let line = line.split_once('#').map_or(line, |(line, _)| line);
+            let line = line.trim();
+            (!line.is_empty()).then(|| {
+                line.parse().with_context(|| format!("line {}", index + 1))
+            })
+        })
+        .collect()
+}
+
+/// The prefixes [`sync`] inserted and removed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub added: Vec<Prefix>,
+    pub removed: Vec<Prefix>,
+}
+
+/// Makes `map` hold exactly the `desired` prefixes.
+///
+/// Prefixes that are already present are left untouched, and new prefixes are
+/// inserted before stale ones are removed, so replacing a prefix with a wider
+/// one never lets traffic through in between.
+pub fn sync(
+    map: &mut impl PrefixMap,
+    desired: &BTreeSet<Prefix>,
+) -> Result<Changes, MapError> {
+    let current = map.prefixes()?;
+    let mut changes = Changes::default();
+    for &prefix in desired.difference(&current) {
+        map.insert(prefix)?;
+        changes.added.push(prefix);
+    }
+    for &prefix in current.difference(desired) {
+        map.remove(prefix)?;
+        changes.removed.push(prefix);
+    }
+    Ok(changes)
+}
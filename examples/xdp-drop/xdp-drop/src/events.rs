@@ -0,0 +1,79 @@
+//! Drop events read from the `EVENTS` ring buffer.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use xdp_drop_common::{PacketLog, reason};
+
+/// A [`PacketLog`] decoded for display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub source: IpAddr,
+    pub action: &'static str,
+    pub reason: &'static str,
+}
+
+impl Event {
+    /// Decodes a ring buffer record, which must be exactly one `PacketLog`.
+    pub fn parse(record: &[u8]) -> Option<Self> {
+        if record.len() != size_of::<PacketLog>() {
+            return None;
+        }
+        // SAFETY: the length was checked above and PacketLog is Pod.
+        let log: PacketLog =
+            unsafe { record.as_ptr().cast::<PacketLog>().read_unaligned() };
+        Some(Self::from(log))
+    }
+
+    /// Formats the event as a single line of JSON, stamped with the time it
+    /// was received.
+    ///
+    /// Every field is an address or a fixed name, so nothing needs escaping.
+    pub fn to_json(&self, received: SystemTime) -> String {
+        let timestamp = received
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        format!(
+            r#"{{"timestamp_ms":{timestamp},"source":"{}","action":"{}","reason":"{}"}}"#,
+            self.source, self.action, self.reason
+        )
+    }
+}
+
+impl From<PacketLog> for Event {
+    fn from(log: PacketLog) -> Self {
+        let action = match log.action {
+            0 => "aborted",
+            1 => "drop",
+            2 => "pass",
+            _ => "unknown",
+        };
+        let reason = match log.reason {
+            reason::BLOCKLIST => "blocklist",
+            reason::RULE => "rule",
+            reason::RATE_LIMIT => "rate-limit",
+            reason::NOT_ALLOWED => "not-allowed",
+            _ => "unknown",
+        };
+        Self {
+            source: Ipv6Addr::from(log.address).to_canonical(),
+            action,
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            source,
+            action,
+            reason,
+        } = self;
+        write!(f, "{action} {source} ({reason})")
+    }
+}
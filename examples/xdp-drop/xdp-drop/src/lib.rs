@@ -0,0 +1,3 @@
+pub mod blocklist;
+pub mod events;
+pub mod rules;
@@ -1,18 +1,416 @@
-use anyhow::Context;
+use anyhow::{Context, ensure};
 use aya::{
-    maps::HashMap,
-    programs::{Xdp, XdpMode},
+    maps::{
+        Array, HashMap, LpmTrie, Map, MapData, MapError, PerCpuArray, RingBuf,
+    },
+    programs::{Xdp, links::FdLink},
 };
 use aya_log::EbpfLogger;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use example_utils::{
+    attach::{self, AttachMode},
+    prefix::Prefix,
+};
 use log::{info, warn};
-use std::net::Ipv4Addr;
-use tokio::signal;
+use std::{
+    collections::{self, BTreeSet},
+    ffi::CString,
+    fs, io,
+    mem::{self, MaybeUninit},
+    net::Ipv6Addr,
+    os::unix::ffi::OsStrExt as _,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{Interest, unix::AsyncFd},
+    signal::{self, unix::SignalKind},
+    time::{self, Instant},
+};
+use xdp_drop::{
+    blocklist::{self, Blocklist, PrefixMap},
+    events::Event,
+    rules::Rule,
+};
+use xdp_drop_common::{
+    Bucket, Policy, RateLimit, RuleKey, STATS_ENTRIES, Stats, exception, mode,
+};
 
 #[derive(Debug, Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Opt {
-    #[clap(short, long, default_value = "eth0")]
-    iface: String,
+    /// Interface to attach to. May be given multiple times.
+    #[clap(short, long, default_value = "eth0", conflicts_with = "all")]
+    iface: Vec<String>,
+    /// Attach to every interface other than loopback.
+    #[clap(long)]
+    all: bool,
+    /// How to attach the program. Not to be confused with --mode, which is
+    /// the firewall's policy.
+    #[clap(long, value_enum, default_value_t = AttachMode::Auto)]
+    xdp_mode: AttachMode,
+    /// Whether to pass or drop traffic that isn't matched by --block, --rule
+    /// or --rate-limit.
+    #[clap(long, value_enum, default_value_t = Mode::Block)]
+    mode: Mode,
+    /// In allow mode, pass traffic whose source address is in this prefix.
+    /// May be given multiple times.
+    #[clap(short, long)]
+    allow: Vec<Prefix>,
+    /// In allow mode, drop ARP and IPv6 neighbor discovery from sources that
+    /// aren't allowed. Without them the host can't resolve its neighbors.
+    #[clap(long)]
+    no_arp_exception: bool,
+    /// In allow mode, drop TCP segments with the ACK flag set from sources that
+    /// aren't allowed, including replies to connections made by this host.
+    #[clap(long)]
+    no_established_exception: bool,
+    /// Drop traffic whose source address is in this prefix, e.g. 10.0.0.0/8
+    /// or 2001:db8::/32. A bare address is treated as a single host. May be
+    /// given multiple times.
+    #[clap(short, long)]
+    block: Vec<Prefix>,
+    /// Also drop traffic from the prefixes listed in this file, one per line.
+    /// The file is read again on SIGHUP and the blocklist updated to match.
+    #[clap(long)]
+    blocklist_file: Option<PathBuf>,
+    /// Drop TCP or UDP traffic by destination port and, optionally, source
+    /// prefix, e.g. "udp/53 from 192.0.2.0/24" or "tcp/22". Without a port
+    /// the rule matches any port. May be given multiple times.
+    #[clap(short, long)]
+    rule: Vec<Rule>,
+    /// Drop packets from any single source address beyond this many packets
+    /// per second.
+    #[clap(
+        long,
+        value_name = "PPS",
+        value_parser = clap::value_parser!(u64).range(1..=1_000_000_000)
+    )]
+    rate_limit: Option<u64>,
+    /// Number of packets a source may send in a burst above --rate-limit.
+    /// Defaults to one second's worth.
+    #[clap(
+        long,
+        value_name = "PACKETS",
+        requires = "rate_limit",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    burst: Option<u64>,
+    /// Seconds between packet counter summaries.
+    #[clap(
+        long,
+        default_value = "10",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    stats_interval: u64,
+    /// Print each dropped packet to stdout as a line of JSON instead of
+    /// logging it.
+    #[clap(long, conflicts_with = "detach")]
+    json: bool,
+    /// Pin the program and its maps under --pin-path and exit, leaving the
+    /// firewall running. Use the `blocklist` subcommand to change what it
+    /// blocks and `detach` to remove it.
+    #[clap(long)]
+    detach: bool,
+    #[clap(flatten)]
+    pin: PinOpt,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Mode {
+    /// Pass everything that isn't blocked.
+    Block,
+    /// Drop everything that isn't from an --allow prefix, other than ARP and
+    /// established TCP connections.
+    Allow,
+}
+
+#[derive(Debug, Args)]
+struct PinOpt {
+    /// BPF filesystem directory holding the pins of a detached firewall.
+    #[clap(long, default_value = "/sys/fs/bpf/xdp-drop")]
+    pin_path: PathBuf,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Change what a firewall started with --detach blocks.
+    Blocklist {
+        #[clap(flatten)]
+        pin: PinOpt,
+        #[clap(subcommand)]
+        command: BlocklistCommand,
+    },
+    /// Remove a firewall started with --detach from its interface.
+    Detach(PinOpt),
+}
+
+#[derive(Debug, Subcommand)]
+enum BlocklistCommand {
+    /// Drop traffic from these prefixes.
+    Add {
+        #[clap(required = true)]
+        prefixes: Vec<Prefix>,
+    },
+    /// Stop dropping traffic from these prefixes.
+    Remove {
+        #[clap(required = true)]
+        prefixes: Vec<Prefix>,
+    },
+    /// Print the blocked prefixes.
+    List,
+    /// Replace the blocked prefixes with those listed in a file.
+    Sync { file: PathBuf },
+}
+
+impl Opt {
+    /// All the prefixes to block: the `--block` flags plus the contents of
+    /// `--blocklist-file`.
+    fn blocklist(&self) -> anyhow::Result<BTreeSet<Prefix>> {
+        let mut prefixes: BTreeSet<_> = self.block.iter().copied().collect();
+        if let Some(path) = &self.blocklist_file {
+            prefixes.extend(read_blocklist_file(path)?);
+        }
+        Ok(prefixes)
+    }
+
+    /// The `POLICY` entry for the chosen mode and exceptions.
+    fn policy(&self) -> Policy {
+        match self.mode {
+            Mode::Block => Policy::default(),
+            Mode::Allow => {
+                let mut exceptions = 0;
+                if !self.no_arp_exception {
+                    exceptions |= exception::NEIGHBOR_DISCOVERY;
+                }
+                if !self.no_established_exception {
+                    exceptions |= exception::ESTABLISHED;
+                }
+                Policy {
+                    mode: mode::ALLOW,
+                    exceptions,
+                }
+            }
+        }
+    }
+}
+
+fn read_blocklist_file(path: &Path) -> anyhow::Result<BTreeSet<Prefix>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    blocklist::parse(&contents)
+        .with_context(|| format!("failed to parse {}", path.display()))
+}
+
+fn sync_blocklist(
+    blocklist: &mut impl PrefixMap,
+    prefixes: &BTreeSet<Prefix>,
+) -> anyhow::Result<()> {
+    let blocklist::Changes { added, removed } =
+        blocklist::sync(blocklist, prefixes)?;
+    for prefix in added {
+        info!("blocking {prefix}");
+    }
+    for prefix in removed {
+        info!("unblocking {prefix}");
+    }
+    Ok(())
+}
+
+fn blocklist_command(
+    pin_path: &Path,
+    command: &BlocklistCommand,
+) -> anyhow::Result<()> {
+    let mut blocklist = Blocklist::from_pin(pin_path).with_context(|| {
+        format!("failed to open the blocklist in {}", pin_path.display())
+    })?;
+    match command {
+        BlocklistCommand::Add { prefixes } => {
+            for &prefix in prefixes {
+                blocklist.insert(prefix)?;
+                info!("blocking {prefix}");
+            }
+        }
+        BlocklistCommand::Remove { prefixes } => {
+            for &prefix in prefixes {
+                blocklist
+                    .remove(prefix)
+                    .with_context(|| format!("failed to unblock {prefix}"))?;
+                info!("unblocking {prefix}");
+            }
+        }
+        BlocklistCommand::List => {
+            for prefix in blocklist.prefixes()? {
+                println!("{prefix}");
+            }
+        }
+        BlocklistCommand::Sync { file } => {
+            sync_blocklist(&mut blocklist, &read_blocklist_file(file)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// The maps `pin` pins, by name.
+const PINNED_MAPS: [&str; 4] = ["BLOCKLIST", "BLOCKLIST_V6", "RULES", "STATS"];
+
+/// Pins `maps`, named after [`PINNED_MAPS`], and the program's link to each interface as
+/// `link-<interface>`, under `path`, so that they outlive this process. If
+/// that fails, whatever was pinned is removed, which detaches the program.
+fn pin(
+    program: &mut Xdp,
+    maps: [&Map; PINNED_MAPS.len()],
+    attachments: Vec<attach::Attachment>,
+    path: &Path,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(path)?;
+    let pin_all = || -> anyhow::Result<()> {
+        ensure!(
+            on_bpffs(path)?,
+            "{} is not on the BPF filesystem",
+            path.display()
+        );
+        for (name, map) in PINNED_MAPS.into_iter().zip(maps) {
+            map.pin(path.join(name))?;
+        }
+        for attach::Attachment { iface, link_id, .. } in attachments {
+            let link = FdLink::try_from(program.take_link(link_id)?).context(
+                "pinning needs an XDP link, which requires Linux 5.9",
+            )?;
+            link.pin(path.join(format!("link-{iface}")))?;
+        }
+        Ok(())
+    };
+    pin_all().inspect_err(|_| {
+        if let Err(e) = unpin(path) {
+            warn!("failed to remove the pins in {}: {e:#}", path.display());
+        }
+    })
+}
+
+/// Whether `path` is on the BPF filesystem, where pins live.
+fn on_bpffs(path: &Path) -> io::Result<bool> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_type as u64 == libc::BPF_FS_MAGIC as u64)
+}
+
+/// Removes the pins created by `pin`, and then `path` itself, which fails if
+/// anything else was put there.
+fn unpin(path: &Path) -> anyhow::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if PINNED_MAPS.contains(&&*name) || name.starts_with("link-") {
+            fs::remove_file(path.join(&*name))?;
+        }
+    }
+    fs::remove_dir(path)?;
+    Ok(())
+}
+
+/// Removes the pins created by `pin`. Once the links are unpinned nothing
+/// refers to them any more, so the program is detached from every interface.
+fn detach(path: &Path) -> anyhow::Result<()> {
+    // Refuse to delete anything from a directory `pin` can't have made.
+    let on_bpffs = on_bpffs(path)
+        .with_context(|| format!("failed to check {}", path.display()))?;
+    ensure!(on_bpffs, "{} is not on the BPF filesystem", path.display());
+    unpin(path)
+        .with_context(|| format!("failed to remove {}", path.display()))?;
+    info!("detached the firewall pinned in {}", path.display());
+    Ok(())
+}
+
+/// `STATS` indices, i.e. `xdp_action` values, and the names they're reported
+/// under.
+const ACTIONS: [(usize, &str); 3] =
+    [(2, "passed"), (1, "dropped"), (0, "aborted")];
+
+/// Sums each `STATS` entry across all CPUs.
+fn read_stats(
+    stats: &PerCpuArray<MapData, Stats>,
+) -> Result<[Stats; STATS_ENTRIES as usize], MapError> {
+    let mut totals = [Stats::default(); STATS_ENTRIES as usize];
+    for (index, total) in (0..).zip(&mut totals) {
+        for cpu in stats.get(&index, 0)?.iter() {
+            total.packets += cpu.packets;
+            total.bytes += cpu.bytes;
+        }
+    }
+    Ok(totals)
+}
+
+fn log_stats(previous: &[Stats], current: &[Stats], period: Duration) {
+    let summary: Vec<_> = ACTIONS
+        .iter()
+        .map(|&(index, name)| {
+            let Stats { packets, bytes } = current[index];
+            let pps = (packets - previous[index].packets) as f64
+                / period.as_secs_f64();
+            format!("{name}: {packets} packets, {bytes} bytes ({pps:.0} pps)")
+        })
+        .collect();
+    info!("{}", summary.join("; "));
+}
+
+/// Reports the sources that were throttled since the last call, given the
+/// throttled packet counts seen then.
+fn log_throttled(
+    buckets: &HashMap<MapData, [u8; 16], Bucket>,
+    previous: &mut collections::HashMap<[u8; 16], u64>,
+) -> Result<(), MapError> {
+    let mut current = collections::HashMap::new();
+    for entry in buckets.iter() {
+        let (source, Bucket { throttled, .. }) = entry?;
+        if throttled == 0 {
+            continue;
+        }
+        // A bucket that was evicted and recreated starts counting from zero.
+        let before = previous.get(&source).copied().unwrap_or(0);
+        let delta = throttled.checked_sub(before).unwrap_or(throttled);
+        if delta > 0 {
+            let source = Ipv6Addr::from(source).to_canonical();
+            info!("throttled {source}: {delta} packets");
+        }
+        current.insert(source, throttled);
+    }
+    *previous = current;
+    Ok(())
+}
+
+/// Reports the drop events in `EVENTS` as they arrive, until the program
+/// exits.
+fn spawn_event_reader(
+    ring: RingBuf<MapData>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let mut ring = AsyncFd::with_interest(ring, Interest::READABLE)?;
+    tokio::task::spawn(async move {
+        loop {
+            let mut guard = ring.readable_mut().await.unwrap();
+            let ring = guard.get_inner_mut();
+            while let Some(record) = ring.next() {
+                let Some(event) = Event::parse(&record) else {
+                    warn!("ignoring a {} byte event", record.len());
+                    continue;
+                };
+                if json {
+                    println!("{}", event.to_json(SystemTime::now()));
+                } else {
+                    info!("{event}");
+                }
+            }
+            guard.clear_ready();
+        }
+    });
+    Ok(())
 }
 
 #[tokio::main]
@@ -21,6 +419,26 @@ async fn main() -> Result<(), anyhow::Error> {
 
     env_logger::init();
 
+    match &opt.command {
+        Some(Command::Blocklist { pin, command }) => {
+            return blocklist_command(&pin.pin_path, command);
+        }
+        Some(Command::Detach(pin)) => return detach(&pin.pin_path),
+        None => {}
+    }
+    ensure!(
+        opt.allow.is_empty() || matches!(opt.mode, Mode::Allow),
+        "--allow has no effect without --mode allow"
+    );
+    let pin_path = &opt.pin.pin_path;
+    if opt.detach {
+        ensure!(
+            !pin_path.exists(),
+            "{} already exists, is a detached firewall already running?",
+            pin_path.display()
+        );
+    }
+
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
@@ -51,23 +469,128 @@ async fn main() -> Result<(), anyhow::Error> {
     let program: &mut Xdp =
         bpf.program_mut("xdp_firewall").unwrap().try_into()?;
     program.load()?;
-    program.attach(&opt.iface, XdpMode::default())
-        .context("failed to attach the XDP program with default mode - try changing XdpMode::default() to XdpMode::Skb")?;
+    let ifaces = attach::interfaces(&opt.iface, opt.all)?;
+    let mut attachments = attach::attach_all(program, &ifaces, opt.xdp_mode)?;
+
+    // The maps are kept as such, rather than converted, so that `--detach`
+    // can pin them once they're filled in.
+    let mut blocklist_v4 = bpf.take_map("BLOCKLIST").unwrap();
+    let mut blocklist_v6 = bpf.take_map("BLOCKLIST_V6").unwrap();
+    let mut rules_map = bpf.take_map("RULES").unwrap();
+    let stats_map = bpf.take_map("STATS").unwrap();
 
     // (1)
-    let mut blocklist: HashMap<_, u32, u32> =
-        HashMap::try_from(bpf.map_mut("BLOCKLIST").unwrap())?;
+    let mut blocklist = Blocklist::new(
+        LpmTrie::try_from(&mut blocklist_v4)?,
+        LpmTrie::try_from(&mut blocklist_v6)?,
+    );
 
     // (2)
-    let block_addr: u32 = Ipv4Addr::new(1, 1, 1, 1).into();
+    sync_blocklist(&mut blocklist, &opt.blocklist()?)?;
+
+    // Fill in the allowlist before switching to allow mode, so that allowed
+    // sources are never dropped.
+    let mut allowlist = Blocklist::new(
+        LpmTrie::try_from(bpf.take_map("ALLOWLIST").unwrap())?,
+        LpmTrie::try_from(bpf.take_map("ALLOWLIST_V6").unwrap())?,
+    );
+    for &prefix in &opt.allow {
+        allowlist.insert(prefix)?;
+        info!("allowing {prefix}");
+    }
+    let mut policy: Array<_, Policy> =
+        Array::try_from(bpf.map_mut("POLICY").unwrap())?;
+    policy.set(0, opt.policy(), 0)?;
+    if let Mode::Allow = opt.mode {
+        info!("dropping everything else");
+    }
 
-    // (3)
-    blocklist.insert(block_addr, 0, 0)?;
+    let mut rules: LpmTrie<_, RuleKey, u32> =
+        LpmTrie::try_from(&mut rules_map)?;
+    for rule in &opt.rule {
+        rules
+            .insert(&rule.key(), 0, 0)
+            .with_context(|| format!("failed to add rule {rule}"))?;
+        info!("dropping {rule}");
+    }
 
+    if let Some(pps) = opt.rate_limit {
+        let burst = opt.burst.unwrap_or(pps);
+        let cost = 1_000_000_000 / pps;
+        let mut rate_limit: Array<_, RateLimit> =
+            Array::try_from(bpf.map_mut("RATE_LIMIT").unwrap())?;
+        rate_limit.set(
+            0,
+            RateLimit {
+                cost,
+                capacity: cost.saturating_mul(burst),
+            },
+            0,
+        )?;
+        info!("limiting each source to {pps} pps, bursts of {burst}");
+    }
+
+    if opt.detach {
+        // (4)
+        let maps = [&blocklist_v4, &blocklist_v6, &rules_map, &stats_map];
+        let program: &mut Xdp =
+            bpf.program_mut("xdp_firewall").unwrap().try_into()?;
+        pin(program, maps, mem::take(&mut attachments), pin_path)
+            .with_context(|| {
+                format!("failed to pin the firewall in {}", pin_path.display())
+            })?;
+        info!(
+            "firewall pinned in {}, run `xdp-drop detach` to remove it",
+            pin_path.display()
+        );
+        return Ok(());
+    }
+
+    let stats: PerCpuArray<_, Stats> = PerCpuArray::try_from(stats_map)?;
+    let period = Duration::from_secs(opt.stats_interval);
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    let mut previous = read_stats(&stats)?;
+    let buckets: HashMap<_, [u8; 16], Bucket> =
+        HashMap::try_from(bpf.take_map("BUCKETS").unwrap())?;
+    let mut throttled = collections::HashMap::new();
+
+    spawn_event_reader(
+        RingBuf::try_from(bpf.take_map("EVENTS").unwrap())?,
+        opt.json,
+    )?;
+
+    let mut sighup = signal::unix::signal(SignalKind::hangup())?;
     let ctrl_c = signal::ctrl_c();
+    tokio::pin!(ctrl_c);
     info!("Waiting for Ctrl-C...");
-    ctrl_c.await?;
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => {
+                // (3)
+                info!("reloading blocklist");
+                let reload = opt.blocklist().and_then(|prefixes| {
+                    sync_blocklist(&mut blocklist, &prefixes)
+                });
+                if let Err(e) = reload {
+                    warn!("failed to reload blocklist: {e:#}");
+                }
+            }
+            _ = interval.tick() => {
+                let current = read_stats(&stats)?;
+                log_stats(&previous, &current, period);
+                previous = current;
+                log_throttled(&buckets, &mut throttled)?;
+            }
+            res = &mut ctrl_c => {
+                res?;
+                break;
+            }
+        }
+    }
     info!("Exiting...");
+    let program: &mut Xdp =
+        bpf.program_mut("xdp_firewall").unwrap().try_into()?;
+    attach::detach_all(program, attachments);
 
     Ok(())
 }
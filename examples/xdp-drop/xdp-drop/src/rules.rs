@@ -0,0 +1,119 @@
+//! Rules held in the firewall's `RULES` map, which drop traffic by source
+//! prefix, L4 protocol and destination port.
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use anyhow::{anyhow, bail, ensure};
+use aya::maps::lpm_trie::Key;
+use example_utils::prefix::Prefix;
+use xdp_drop_common::RuleKey;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    /// The IANA protocol number, as found in the IP header.
+    pub fn number(self) -> u8 {
+        match self {
+            Self::Tcp => 6,
+            Self::Udp => 17,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+        })
+    }
+}
+
+/// A rule such as `udp/53 from 192.0.2.0/24`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub protocol: Protocol,
+    /// The destination port, or `None` for any port.
+    pub port: Option<u16>,
+    /// The source prefix, or `None` for any source.
+    pub source: Option<Prefix>,
+}
+
+impl Rule {
+    pub fn key(&self) -> Key<RuleKey> {
+        let (addr, len) = match self.source {
+            None => ([0; 16], 0),
+            Some(prefix) => {
+                let len = u32::from(prefix.prefix_len());
+                match prefix.addr() {
+                    IpAddr::V4(addr) => {
+                        (addr.to_ipv6_mapped().octets(), 96 + len)
+                    }
+                    IpAddr::V6(addr) => (addr.octets(), len),
+                }
+            }
+        };
+        let port = self.port.unwrap_or(0);
+        Key::new(
+            RuleKey::FIXED_BITS + len,
+            RuleKey::new(self.protocol.number(), port, addr),
+        )
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    /// Parses `<tcp|udp>[/<port>] [from <prefix>]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let l4 = words.next().ok_or_else(|| anyhow!("empty rule"))?;
+        let source = match (words.next(), words.next(), words.next()) {
+            (None, None, None) => None,
+            (Some("from"), Some(prefix), None) => Some(prefix.parse()?),
+            _ => bail!("expected <tcp|udp>[/<port>] [from <prefix>]"),
+        };
+        let (protocol, port) = l4.split_once('/').unwrap_or((l4, ""));
+        let protocol = match protocol.to_ascii_lowercase().as_str() {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            _ => bail!("unsupported protocol {protocol}, expected tcp or udp"),
+        };
+        let port = if port.is_empty() {
+            None
+        } else {
+            let port = port
+                .parse()
+                .map_err(|e| anyhow!("invalid port {port}: {e}"))?;
+            ensure!(port != 0, "port must be between 1 and 65535");
+            Some(port)
+        };
+        Ok(Self {
+            protocol,
+            port,
+            source,
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            protocol,
+            port,
+            source,
+        } = self;
+        write!(f, "{protocol}")?;
+        if let Some(port) = port {
+            write!(f, "/{port}")?;
+        }
+        if let Some(source) = source {
+            write!(f, " from {source}")?;
+        }
+        Ok(())
+    }
+}
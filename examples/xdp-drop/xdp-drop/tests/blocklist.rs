@@ -0,0 +1,238 @@
+//! Tests for the checks `xdp_firewall` makes on well-formed packets.
+
+mod common;
+
+use std::net::Ipv4Addr;
+
+use aya::{
+    maps::{Array, HashMap, LpmTrie, RingBuf, lpm_trie::Key},
+    programs::Xdp,
+};
+use common::*;
+use xdp_drop::{events::Event, rules::Rule};
+use xdp_drop_common::{
+    Bucket, Policy, RateLimit, RuleKey, exception, ipv4_mapped, mode,
+};
+
+#[test]
+fn empty_blocklist_passes_everything() {
+    let bpf = load();
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let frame = ipv4_frame(Ipv4Addr::new(10, 1, 2, 3), UDP, 53);
+    assert_eq!(test_run(program, &frame), XDP_PASS);
+    let frame = ipv6_frame("2001:db8::2".parse().unwrap(), TCP, 443);
+    assert_eq!(test_run(program, &frame), XDP_PASS);
+}
+
+#[test]
+fn prefixes_match_contained_sources() {
+    let mut bpf = load();
+    block(&mut bpf, Ipv4Addr::new(10, 0, 0, 0), 8);
+    block(&mut bpf, Ipv4Addr::new(192, 0, 2, 0), 24);
+    block(&mut bpf, Ipv4Addr::new(198, 51, 100, 7), 32);
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    for (src, action) in [
+        (Ipv4Addr::new(10, 0, 0, 0), XDP_DROP),
+        (Ipv4Addr::new(10, 255, 255, 255), XDP_DROP),
+        (Ipv4Addr::new(11, 0, 0, 1), XDP_PASS),
+        (Ipv4Addr::new(192, 0, 2, 200), XDP_DROP),
+        (Ipv4Addr::new(192, 0, 3, 1), XDP_PASS),
+        (Ipv4Addr::new(198, 51, 100, 7), XDP_DROP),
+        (Ipv4Addr::new(198, 51, 100, 8), XDP_PASS),
+    ] {
+        let frame = ipv4_frame(src, UDP, 53);
+        assert_eq!(test_run(program, &frame), action, "{src}");
+    }
+}
+
+#[test]
+fn ipv6_prefixes_match_contained_sources() {
+    let mut bpf = load();
+    block_v6(&mut bpf, "2001:db8:bad::".parse().unwrap(), 48);
+    // A catch-all IPv4 prefix must not match IPv6 sources.
+    block(&mut bpf, Ipv4Addr::new(0, 0, 0, 0), 0);
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    for (src, action) in [
+        ("2001:db8:bad::1", XDP_DROP),
+        ("2001:db8:bad:ffff::1", XDP_DROP),
+        ("2001:db8:bae::1", XDP_PASS),
+        ("::ffff:10.0.0.1", XDP_PASS),
+    ] {
+        let frame = ipv6_frame(src.parse().unwrap(), UDP, 53);
+        assert_eq!(test_run(program, &frame), action, "{src}");
+    }
+}
+
+#[test]
+fn rules_match_protocol_port_and_source() {
+    let mut bpf = load();
+    let mut rules: LpmTrie<_, RuleKey, u32> =
+        LpmTrie::try_from(bpf.map_mut("RULES").unwrap()).unwrap();
+    for rule in [
+        "udp/53 from 192.0.2.0/24",
+        "tcp/22",
+        "tcp from 2001:db8::/32",
+    ] {
+        let rule: Rule = rule.parse().unwrap();
+        rules.insert(&rule.key(), 0, 0).unwrap();
+    }
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let inside = Ipv4Addr::new(192, 0, 2, 10);
+    let outside = Ipv4Addr::new(198, 51, 100, 1);
+    for (frame, action) in [
+        (ipv4_frame(inside, UDP, 53), XDP_DROP),
+        (ipv4_frame(inside, UDP, 54), XDP_PASS),
+        (ipv4_frame(inside, TCP, 53), XDP_PASS),
+        (ipv4_frame(outside, UDP, 53), XDP_PASS),
+        (ipv4_frame(outside, TCP, 22), XDP_DROP),
+        (
+            ipv6_frame("2001:db8::5".parse().unwrap(), TCP, 22),
+            XDP_DROP,
+        ),
+        (
+            ipv6_frame("2001:db8::5".parse().unwrap(), TCP, 8080),
+            XDP_DROP,
+        ),
+        (
+            ipv6_frame("2001:db8::5".parse().unwrap(), UDP, 8080),
+            XDP_PASS,
+        ),
+        (
+            ipv6_frame("2001:db9::5".parse().unwrap(), TCP, 8080),
+            XDP_PASS,
+        ),
+    ] {
+        assert_eq!(test_run(program, &frame), action, "{frame:02x?}");
+    }
+}
+
+#[test]
+fn drops_are_reported_as_events() {
+    let mut bpf = load();
+    block(&mut bpf, Ipv4Addr::new(10, 0, 0, 0), 8);
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    for src in [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(11, 0, 0, 1)] {
+        test_run(program, &ipv4_frame(src, UDP, 53));
+    }
+
+    let mut events = RingBuf::try_from(bpf.map_mut("EVENTS").unwrap()).unwrap();
+    let event = Event::parse(&events.next().unwrap()).unwrap();
+    assert_eq!(event.to_string(), "drop 10.0.0.1 (blocklist)");
+    // Passed packets aren't reported.
+    assert!(events.next().is_none());
+}
+
+#[test]
+fn sources_are_throttled_beyond_their_burst() {
+    const BURST: u64 = 3;
+    let mut bpf = load();
+    // One packet per second, so the bucket doesn't refill during the test.
+    let cost = 1_000_000_000;
+    let mut rate_limit: Array<_, RateLimit> =
+        Array::try_from(bpf.map_mut("RATE_LIMIT").unwrap()).unwrap();
+    rate_limit
+        .set(
+            0,
+            RateLimit {
+                cost,
+                capacity: cost * BURST,
+            },
+            0,
+        )
+        .unwrap();
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let source = Ipv4Addr::new(192, 0, 2, 7);
+    let frame = ipv4_frame(source, UDP, 53);
+    for _ in 0..BURST {
+        assert_eq!(test_run(program, &frame), XDP_PASS);
+    }
+    assert_eq!(test_run(program, &frame), XDP_DROP);
+
+    let mut events = RingBuf::try_from(bpf.map_mut("EVENTS").unwrap()).unwrap();
+    let event = Event::parse(&events.next().unwrap()).unwrap();
+    assert_eq!(event.to_string(), "drop 192.0.2.7 (rate-limit)");
+    assert!(events.next().is_none());
+    drop(events);
+    let buckets: HashMap<_, [u8; 16], Bucket> =
+        HashMap::try_from(bpf.map("BUCKETS").unwrap()).unwrap();
+    let bucket = buckets.get(&ipv4_mapped(source.octets()), 0).unwrap();
+    assert_eq!(bucket.throttled, 1);
+}
+
+#[test]
+fn allow_mode_drops_unlisted_sources() {
+    let mut bpf = load();
+    let mut allowlist: LpmTrie<_, u32, u32> =
+        LpmTrie::try_from(bpf.map_mut("ALLOWLIST").unwrap()).unwrap();
+    allowlist
+        .insert(
+            &Key::new(24, u32::from(Ipv4Addr::new(192, 0, 2, 0)).to_be()),
+            0,
+            0,
+        )
+        .unwrap();
+    set_policy(
+        &mut bpf,
+        Policy {
+            mode: mode::ALLOW,
+            exceptions: exception::NEIGHBOR_DISCOVERY | exception::ESTABLISHED,
+        },
+    );
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let outside = Ipv4Addr::new(198, 51, 100, 1);
+    let mut ack = ipv4_frame(outside, TCP, 40000);
+    ack[IPV4_TCP_FLAGS] = TCP_ACK;
+    for (frame, action) in [
+        (ipv4_frame(Ipv4Addr::new(192, 0, 2, 7), UDP, 53), XDP_PASS),
+        (ipv4_frame(outside, UDP, 53), XDP_DROP),
+        (ipv4_frame(outside, TCP, 22), XDP_DROP),
+        (ack, XDP_PASS),
+        (
+            ipv6_frame("2001:db8::1".parse().unwrap(), UDP, 53),
+            XDP_DROP,
+        ),
+        (icmpv6_frame("fe80::1".parse().unwrap(), 135), XDP_PASS),
+        (icmpv6_frame("fe80::1".parse().unwrap(), 128), XDP_DROP),
+        (arp_frame(), XDP_PASS),
+    ] {
+        assert_eq!(test_run(program, &frame), action, "{frame:02x?}");
+    }
+}
+
+#[test]
+fn allow_mode_exceptions_can_be_disabled() {
+    let mut bpf = load();
+    set_policy(
+        &mut bpf,
+        Policy {
+            mode: mode::ALLOW,
+            exceptions: 0,
+        },
+    );
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let mut ack = ipv4_frame(Ipv4Addr::new(198, 51, 100, 1), TCP, 40000);
+    ack[IPV4_TCP_FLAGS] = TCP_ACK;
+    for frame in [
+        ack,
+        icmpv6_frame("fe80::1".parse().unwrap(), 135),
+        arp_frame(),
+    ] {
+        assert_eq!(test_run(program, &frame), XDP_DROP, "{frame:02x?}");
+    }
+}
@@ -0,0 +1,214 @@
+//! Helpers shared by the tests that run `xdp_firewall` with
+//! `BPF_PROG_TEST_RUN`, which runs the program against a caller-supplied frame
+//! without attaching it to an interface. The harness itself, and the rig that
+//! the end-to-end tests in `veth.rs` and `detach.rs` run the binary in, are in
+//! `example-test-utils`.
+//!
+//! Each test binary uses a different subset of these.
+#![allow(dead_code)]
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use aya::{
+    Ebpf,
+    maps::{Array, LpmTrie, lpm_trie::Key},
+    programs::Xdp,
+};
+use xdp_drop_common::Policy;
+
+pub const XDP_ABORTED: u32 = 0;
+pub const XDP_DROP: u32 = 1;
+pub const XDP_PASS: u32 = 2;
+
+/// Runs `program` once over `frame` and returns the XDP action it chose.
+pub fn test_run(program: &Xdp, frame: &[u8]) -> u32 {
+    example_test_utils::test_run(program.fd().unwrap(), frame)
+}
+
+pub const TCP: u8 = 6;
+pub const UDP: u8 = 17;
+
+/// Builds an empty TCP or UDP segment to `dst_port`.
+pub fn l4_header(proto: u8, dst_port: u16) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&40000u16.to_be_bytes()); // source port
+    header.extend_from_slice(&dst_port.to_be_bytes());
+    match proto {
+        TCP => header.extend_from_slice(&[
+            0, 0, 0, 0, // sequence number
+            0, 0, 0, 0, // acknowledgment number
+            0x50, 0x02, 0xff, 0xff, // data offset, flags (SYN), window
+            0, 0, 0, 0, // checksum, urgent pointer
+        ]),
+        UDP => header.extend_from_slice(&[0, 8, 0, 0]), // length, checksum
+        _ => panic!("unsupported protocol {proto}"),
+    }
+    header
+}
+
+/// Builds an Ethernet + IPv4 frame carrying an empty `proto` segment from
+/// `src` to `dst_port`.
+pub fn ipv4_frame(src: Ipv4Addr, proto: u8, dst_port: u16) -> Vec<u8> {
+    ipv4_packet(src, proto, &l4_header(proto, dst_port))
+}
+
+const ICMP: u8 = 1;
+
+/// Builds an Ethernet + IPv4 frame carrying an ICMP echo request from `src`.
+pub fn icmp_frame(src: Ipv4Addr) -> Vec<u8> {
+    let message = [8, 0, 0, 0, 0, 0, 0, 0]; // type, code, checksum, id, seq
+    ipv4_packet(src, ICMP, &message)
+}
+
+fn ipv4_packet(src: Ipv4Addr, proto: u8, l4: &[u8]) -> Vec<u8> {
+    ipv4_packet_with(&[], 0, src, proto, l4)
+}
+
+/// The "more fragments" flag, in the same field as the fragment offset.
+pub const MORE_FRAGMENTS: u16 = 0x2000;
+
+/// Like `ipv4_packet`, with IPv4 `options` (a multiple of 4 bytes) and the
+/// given flags and fragment offset.
+pub fn ipv4_packet_with(
+    options: &[u8],
+    flags_and_offset: u16,
+    src: Ipv4Addr,
+    proto: u8,
+    l4: &[u8],
+) -> Vec<u8> {
+    let header_len = 20 + options.len() as u16;
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]); // destination MAC
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]); // source MAC
+    frame.extend_from_slice(&0x0800u16.to_be_bytes()); // EtherType::Ipv4
+    frame.extend_from_slice(&[0x40 | (header_len / 4) as u8, 0]); // IHL, DSCP
+    frame.extend_from_slice(&(header_len + l4.len() as u16).to_be_bytes());
+    frame.extend_from_slice(&[0, 0]); // identification
+    frame.extend_from_slice(&flags_and_offset.to_be_bytes());
+    frame.extend_from_slice(&[64, proto, 0, 0]); // TTL, protocol, checksum
+    frame.extend_from_slice(&src.octets());
+    frame.extend_from_slice(&Ipv4Addr::new(192, 0, 2, 1).octets());
+    frame.extend_from_slice(options);
+    frame.extend_from_slice(l4);
+    frame
+}
+
+/// Builds an Ethernet + IPv6 frame carrying an empty `proto` segment from
+/// `src` to `dst_port`.
+pub fn ipv6_frame(src: Ipv6Addr, proto: u8, dst_port: u16) -> Vec<u8> {
+    ipv6_packet(src, proto, &l4_header(proto, dst_port))
+}
+
+const ICMPV6: u8 = 58;
+
+/// Builds an Ethernet + IPv6 frame carrying an ICMPv6 message of the given
+/// type from `src`.
+pub fn icmpv6_frame(src: Ipv6Addr, icmp_type: u8) -> Vec<u8> {
+    let message = [icmp_type, 0, 0, 0, 0, 0, 0, 0]; // code, checksum, reserved
+    ipv6_packet(src, ICMPV6, &message)
+}
+
+/// Builds an Ethernet + IPv6 frame from `src` whose next header is
+/// `next_hdr`, followed by `payload`.
+pub fn ipv6_packet(src: Ipv6Addr, next_hdr: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]); // destination MAC
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]); // source MAC
+    frame.extend_from_slice(&0x86ddu16.to_be_bytes()); // EtherType::Ipv6
+    frame.extend_from_slice(&[0x60, 0, 0, 0]); // version, class, flow label
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(&[next_hdr, 64]); // next header, hop limit
+    frame.extend_from_slice(&src.octets());
+    frame.extend_from_slice(
+        &"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets(),
+    );
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub const HOP_BY_HOP: u8 = 0;
+pub const ROUTING: u8 = 43;
+pub const FRAGMENT: u8 = 44;
+pub const DESTINATION_OPTIONS: u8 = 60;
+
+/// Builds a hop-by-hop, routing or destination options header that is `len`
+/// bytes long, a multiple of 8, followed by `payload`. The options are all
+/// padding.
+pub fn ipv6_ext(len: usize, next_hdr: u8, payload: &[u8]) -> Vec<u8> {
+    assert!(
+        len >= 8 && len % 8 == 0,
+        "bad extension header length {len}"
+    );
+    let mut packet = vec![next_hdr, (len / 8 - 1) as u8];
+    packet.resize(len, 0); // Pad1 options
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Builds an IPv6 fragment header, followed by `payload`. `offset` is in
+/// 8-byte units.
+pub fn ipv6_fragment(
+    offset: u16,
+    more: bool,
+    next_hdr: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let offset_flags = offset << 3 | u16::from(more);
+    let mut packet = vec![next_hdr, 0];
+    packet.extend_from_slice(&offset_flags.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0x12, 0x34]); // identification
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Builds an ARP request for 192.0.2.1.
+pub fn arp_frame() -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&[0xff; 6]); // destination MAC
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]); // source MAC
+    frame.extend_from_slice(&0x0806u16.to_be_bytes()); // EtherType::Arp
+    frame.extend_from_slice(&[
+        0, 1, 0x08, 0, 6, 4, 0, 1, // Ethernet, IPv4, request
+        0x02, 0, 0, 0, 0, 0x02, 192, 0, 2, 2, // sender
+        0, 0, 0, 0, 0, 0, 192, 0, 2, 1, // target
+    ]);
+    frame
+}
+
+/// Offset of the TCP flags in the frames built by `ipv4_frame`.
+pub const IPV4_TCP_FLAGS: usize = 14 + 20 + 13;
+pub const TCP_ACK: u8 = 0x10;
+
+pub fn load() -> Ebpf {
+    let mut bpf = Ebpf::load(aya::include_bytes_aligned!(concat!(
+        env!("OUT_DIR"),
+        "/xdp-drop"
+    )))
+    .unwrap();
+    let program: &mut Xdp =
+        bpf.program_mut("xdp_firewall").unwrap().try_into().unwrap();
+    program.load().unwrap();
+    bpf
+}
+
+pub fn block(bpf: &mut Ebpf, addr: Ipv4Addr, len: u32) {
+    let mut blocklist: LpmTrie<_, u32, u32> =
+        LpmTrie::try_from(bpf.map_mut("BLOCKLIST").unwrap()).unwrap();
+    blocklist
+        .insert(&Key::new(len, u32::from(addr).to_be()), 0, 0)
+        .unwrap();
+}
+
+pub fn block_v6(bpf: &mut Ebpf, addr: Ipv6Addr, len: u32) {
+    let mut blocklist: LpmTrie<_, [u8; 16], u32> =
+        LpmTrie::try_from(bpf.map_mut("BLOCKLIST_V6").unwrap()).unwrap();
+    blocklist
+        .insert(&Key::new(len, addr.octets()), 0, 0)
+        .unwrap();
+}
+
+pub fn set_policy(bpf: &mut Ebpf, policy: Policy) {
+    let mut map: Array<_, Policy> =
+        Array::try_from(bpf.map_mut("POLICY").unwrap()).unwrap();
+    map.set(0, policy, 0).unwrap();
+}
@@ -0,0 +1,124 @@
+//! End-to-end tests for leaving the firewall running with `--detach`, and
+//! managing it afterwards with the `blocklist` and `detach` subcommands.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{self, Command, Output},
+    thread,
+    time::{Duration, Instant},
+};
+
+use example_test_utils::netns::{Netns, XDP_IFACE};
+
+const XDP_DROP: &str = env!("CARGO_BIN_EXE_xdp-drop");
+
+/// A BPF filesystem of the test's own, so that its pins can't clash with those
+/// of a firewall running on the host. It's unmounted when dropped.
+struct Bpffs {
+    dir: PathBuf,
+}
+
+impl Bpffs {
+    fn new(name: &str) -> Self {
+        let dir =
+            env::temp_dir().join(format!("xdp-drop-{name}-{}", process::id()));
+        fs::create_dir(&dir).unwrap();
+        let status = Command::new("mount")
+            .args(["-t", "bpf", "bpf"])
+            .arg(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "mount: {status}");
+        Self { dir }
+    }
+}
+
+impl Drop for Bpffs {
+    fn drop(&mut self) {
+        let _ = Command::new("umount").arg(&self.dir).status();
+        let _ = fs::remove_dir(&self.dir);
+    }
+}
+
+fn xdp_drop(args: &[&str]) -> Output {
+    Command::new(XDP_DROP).args(args).output().unwrap()
+}
+
+/// Runs a subcommand, checks that it succeeded and returns its stdout.
+fn subcommand(args: &[&str]) -> String {
+    let output = xdp_drop(args);
+    assert!(
+        output.status.success(),
+        "{}: {}\n{}",
+        args.join(" "),
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn attached(netns: &Netns) -> bool {
+    netns
+        .run("ip", &["link", "show", XDP_IFACE])
+        .contains("prog/xdp")
+}
+
+#[test]
+fn detached_firewall_outlives_the_process() {
+    let netns = Netns::new();
+    let bpffs = Bpffs::new("detach");
+    let pin_path = bpffs.dir.join("xdp-drop");
+    let pin_path = pin_path.to_str().unwrap();
+
+    netns.run(
+        XDP_DROP,
+        &[
+            "--iface",
+            XDP_IFACE,
+            "--block",
+            "192.0.2.0/24",
+            "--detach",
+            "--pin-path",
+            pin_path,
+        ],
+    );
+    assert!(attached(&netns));
+
+    // The subcommands only open the pins, so they don't need the namespace.
+    subcommand(&[
+        "blocklist",
+        "--pin-path",
+        pin_path,
+        "add",
+        "198.51.100.0/24",
+    ]);
+    let blocked = subcommand(&["blocklist", "--pin-path", pin_path, "list"]);
+    assert_eq!(blocked, "192.0.2.0/24\n198.51.100.0/24\n");
+
+    subcommand(&["detach", "--pin-path", pin_path]);
+    assert!(!Path::new(pin_path).exists());
+    // The link is released asynchronously once its last pin is gone.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while attached(&netns) {
+        assert!(Instant::now() < deadline, "still attached");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn detach_refuses_paths_outside_bpffs() {
+    let dir =
+        env::temp_dir().join(format!("xdp-drop-not-bpffs-{}", process::id()));
+    fs::create_dir(&dir).unwrap();
+    fs::write(dir.join("BLOCKLIST"), "not a pin").unwrap();
+
+    let output = xdp_drop(&["detach", "--pin-path", dir.to_str().unwrap()]);
+    let kept = dir.join("BLOCKLIST").exists();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("not on the BPF filesystem"), "{stderr}");
+    assert!(kept);
+}
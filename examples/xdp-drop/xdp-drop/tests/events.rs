@@ -0,0 +1,62 @@
+//! Tests for decoding and formatting drop events.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use xdp_drop::events::Event;
+use xdp_drop_common::{PacketLog, ipv4_mapped, reason};
+
+fn record(log: PacketLog) -> Vec<u8> {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(
+            (&log as *const PacketLog).cast::<u8>(),
+            size_of::<PacketLog>(),
+        )
+    };
+    bytes.to_vec()
+}
+
+#[test]
+fn parse_unmaps_ipv4_sources() {
+    let event = Event::parse(&record(PacketLog {
+        address: ipv4_mapped([192, 0, 2, 7]),
+        action: 1,
+        reason: reason::RULE,
+    }))
+    .unwrap();
+    assert_eq!(
+        event,
+        Event {
+            source: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7)),
+            action: "drop",
+            reason: "rule",
+        }
+    );
+    assert_eq!(event.to_string(), "drop 192.0.2.7 (rule)");
+}
+
+#[test]
+fn parse_rejects_short_records() {
+    let record = record(PacketLog {
+        address: [0; 16],
+        action: 1,
+        reason: reason::BLOCKLIST,
+    });
+    assert_eq!(Event::parse(&record[..record.len() - 1]), None);
+}
+
+#[test]
+fn json_is_one_line() {
+    let event = Event {
+        source: IpAddr::V6("2001:db8::1".parse::<Ipv6Addr>().unwrap()),
+        action: "drop",
+        reason: "rate-limit",
+    };
+    let received = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+    assert_eq!(
+        event.to_json(received),
+        r#"{"timestamp_ms":1700000000123,"source":"2001:db8::1","action":"drop","reason":"rate-limit"}"#
+    );
+}
@@ -0,0 +1,283 @@
+//! Tests for how `xdp_firewall` parses frames, in particular ones that are cut
+//! short or that it doesn't understand.
+
+mod common;
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use aya::{
+    maps::{LpmTrie, PerCpuArray},
+    programs::Xdp,
+};
+use common::*;
+use xdp_drop::rules::Rule;
+use xdp_drop_common::{RuleKey, Stats};
+
+/// Length of an Ethernet header, and offsets of the transport header in the
+/// frames built by `ipv4_frame` and `ipv6_frame`.
+const ETH: usize = 14;
+const IPV4_L4: usize = ETH + 20;
+const IPV6_L4: usize = ETH + 40;
+
+#[test]
+fn truncated_headers_abort() {
+    let bpf = load();
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let src = Ipv4Addr::new(192, 0, 2, 7);
+    for (frame, len) in [
+        // An Ethernet header with nothing after it.
+        (ipv4_frame(src, UDP, 53), ETH),
+        (ipv6_frame("2001:db8::2".parse().unwrap(), UDP, 53), ETH),
+        // Half an IP header.
+        (ipv4_frame(src, UDP, 53), ETH + 10),
+        (
+            ipv6_frame("2001:db8::2".parse().unwrap(), UDP, 53),
+            ETH + 20,
+        ),
+        // Part of a transport header.
+        (ipv4_frame(src, UDP, 53), IPV4_L4 + 4),
+        (ipv4_frame(src, TCP, 22), IPV4_L4 + 12),
+        (
+            ipv6_frame("2001:db8::2".parse().unwrap(), TCP, 22),
+            IPV6_L4 + 12,
+        ),
+    ] {
+        let frame = &frame[..len];
+        assert_eq!(test_run(program, frame), XDP_ABORTED, "{frame:02x?}");
+    }
+}
+
+#[test]
+fn complete_headers_pass() {
+    let bpf = load();
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let src = Ipv4Addr::new(192, 0, 2, 7);
+    for frame in [
+        ipv4_frame(src, UDP, 53),
+        ipv4_frame(src, TCP, 22),
+        ipv6_frame("2001:db8::2".parse().unwrap(), UDP, 53),
+        ipv6_frame("2001:db8::2".parse().unwrap(), TCP, 22),
+    ] {
+        assert_eq!(test_run(program, &frame), XDP_PASS, "{frame:02x?}");
+    }
+}
+
+#[test]
+fn non_ip_frames_pass() {
+    let bpf = load();
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let mut unknown = arp_frame();
+    // The EtherType reserved for local experiments.
+    unknown[12..14].copy_from_slice(&0x88b5u16.to_be_bytes());
+    for frame in [arp_frame(), unknown] {
+        assert_eq!(test_run(program, &frame), XDP_PASS, "{frame:02x?}");
+    }
+}
+
+#[test]
+fn other_protocols_are_checked_by_source_only() {
+    let mut bpf = load();
+    block(&mut bpf, Ipv4Addr::new(10, 0, 0, 0), 8);
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    assert_eq!(
+        test_run(program, &icmp_frame(Ipv4Addr::new(10, 0, 0, 1))),
+        XDP_DROP
+    );
+    assert_eq!(
+        test_run(program, &icmp_frame(Ipv4Addr::new(192, 0, 2, 7))),
+        XDP_PASS
+    );
+    // Dropping the transport header doesn't matter for protocols whose
+    // header isn't read.
+    let frame = icmp_frame(Ipv4Addr::new(192, 0, 2, 7));
+    assert_eq!(test_run(program, &frame[..IPV4_L4]), XDP_PASS);
+}
+
+#[test]
+fn aborted_frames_are_counted() {
+    let bpf = load();
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let frame = ipv4_frame(Ipv4Addr::new(192, 0, 2, 7), TCP, 22);
+    test_run(program, &frame[..IPV4_L4]);
+    test_run(program, &frame);
+
+    let stats: PerCpuArray<_, Stats> =
+        PerCpuArray::try_from(bpf.map("STATS").unwrap()).unwrap();
+    let count = |action| {
+        stats
+            .get(&action, 0)
+            .unwrap()
+            .iter()
+            .map(|cpu| (cpu.packets, cpu.bytes))
+            .fold((0, 0), |(p, b), (cp, cb)| (p + cp, b + cb))
+    };
+    assert_eq!(count(XDP_ABORTED), (1, IPV4_L4 as u64));
+    assert_eq!(count(XDP_PASS), (1, frame.len() as u64));
+    assert_eq!(count(XDP_DROP), (0, 0));
+}
+
+/// IPv4 options: a router alert, then no-ops up to a multiple of 4 bytes.
+const ROUTER_ALERT: [u8; 4] = [0x94, 0x04, 0, 0];
+const NOP: u8 = 1;
+
+fn add_rule(bpf: &mut aya::Ebpf, rule: &str) {
+    let mut rules: LpmTrie<_, RuleKey, u32> =
+        LpmTrie::try_from(bpf.map_mut("RULES").unwrap()).unwrap();
+    let rule: Rule = rule.parse().unwrap();
+    rules.insert(&rule.key(), 0, 0).unwrap();
+}
+
+#[test]
+fn ipv4_options_move_the_transport_header() {
+    let mut bpf = load();
+    add_rule(&mut bpf, "udp/53");
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let src = Ipv4Addr::new(192, 0, 2, 7);
+    let mut longest = [NOP; 40];
+    longest[..4].copy_from_slice(&ROUTER_ALERT);
+    for options in [&ROUTER_ALERT[..], &[NOP; 8], &longest] {
+        for (port, action) in [(53, XDP_DROP), (54, XDP_PASS)] {
+            let frame =
+                ipv4_packet_with(options, 0, src, UDP, &l4_header(UDP, port));
+            assert_eq!(test_run(program, &frame), action, "{frame:02x?}");
+        }
+    }
+}
+
+#[test]
+fn bad_ipv4_header_lengths_abort() {
+    let bpf = load();
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let src = Ipv4Addr::new(192, 0, 2, 7);
+    let mut too_short = ipv4_frame(src, UDP, 53);
+    too_short[ETH] = 0x44; // IHL 4, shorter than the fixed header
+    let truncated =
+        ipv4_packet_with(&[NOP; 40], 0, src, UDP, &l4_header(UDP, 53));
+    // Cut the frame in the middle of the options.
+    let truncated = &truncated[..IPV4_L4 + 20];
+
+    for frame in [&too_short[..], truncated] {
+        assert_eq!(test_run(program, frame), XDP_ABORTED, "{frame:02x?}");
+    }
+}
+
+#[test]
+fn port_rules_only_match_first_fragments() {
+    let mut bpf = load();
+    add_rule(&mut bpf, "udp/53");
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let src = Ipv4Addr::new(192, 0, 2, 7);
+    let first =
+        ipv4_packet_with(&[], MORE_FRAGMENTS, src, UDP, &l4_header(UDP, 53));
+    assert_eq!(test_run(program, &first), XDP_DROP);
+    // Later fragments whose payload would otherwise be read as port 53, the
+    // last one too short to hold a UDP header at all.
+    for (flags_and_offset, payload) in [
+        (MORE_FRAGMENTS | 185, &l4_header(UDP, 53)[..]),
+        (370, &[0, 53][..]),
+    ] {
+        let frame = ipv4_packet_with(&[], flags_and_offset, src, UDP, payload);
+        assert_eq!(test_run(program, &frame), XDP_PASS, "{frame:02x?}");
+    }
+}
+
+#[test]
+fn ipv6_extension_headers_move_the_transport_header() {
+    let mut bpf = load();
+    add_rule(&mut bpf, "udp/53");
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let src: Ipv6Addr = "2001:db8::2".parse().unwrap();
+    for (port, action) in [(53, XDP_DROP), (54, XDP_PASS)] {
+        let udp = l4_header(UDP, port);
+        let first_fragment = ipv6_fragment(0, true, UDP, &udp);
+        for (next_hdr, headers) in [
+            (HOP_BY_HOP, ipv6_ext(8, UDP, &udp)),
+            (DESTINATION_OPTIONS, ipv6_ext(24, UDP, &udp)),
+            (FRAGMENT, first_fragment.clone()),
+            (
+                HOP_BY_HOP,
+                ipv6_ext(
+                    16,
+                    DESTINATION_OPTIONS,
+                    &ipv6_ext(8, FRAGMENT, &first_fragment),
+                ),
+            ),
+        ] {
+            let frame = ipv6_packet(src, next_hdr, &headers);
+            assert_eq!(test_run(program, &frame), action, "{frame:02x?}");
+        }
+    }
+}
+
+#[test]
+fn port_rules_only_match_first_ipv6_fragments() {
+    let mut bpf = load();
+    add_rule(&mut bpf, "udp/53");
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let src: Ipv6Addr = "2001:db8::2".parse().unwrap();
+    // Later fragments whose payload would otherwise be read as port 53, the
+    // last one too short to hold a UDP header at all.
+    for (next_hdr, headers) in [
+        (FRAGMENT, ipv6_fragment(185, true, UDP, &l4_header(UDP, 53))),
+        (
+            HOP_BY_HOP,
+            ipv6_ext(8, FRAGMENT, &ipv6_fragment(370, false, UDP, &[0, 53])),
+        ),
+    ] {
+        let frame = ipv6_packet(src, next_hdr, &headers);
+        assert_eq!(test_run(program, &frame), XDP_PASS, "{frame:02x?}");
+    }
+}
+
+#[test]
+fn protocol_rules_match_later_fragments() {
+    let mut bpf = load();
+    add_rule(&mut bpf, "udp");
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+
+    let v4 = Ipv4Addr::new(192, 0, 2, 7);
+    let v6: Ipv6Addr = "2001:db8::2".parse().unwrap();
+    for (frame, action) in [
+        (ipv4_packet_with(&[], 185, v4, UDP, &[0, 53]), XDP_DROP),
+        (ipv4_packet_with(&[], 185, v4, TCP, &[0, 53]), XDP_PASS),
+        (
+            ipv6_packet(
+                v6,
+                FRAGMENT,
+                &ipv6_fragment(185, false, UDP, &[0, 53]),
+            ),
+            XDP_DROP,
+        ),
+        (
+            ipv6_packet(
+                v6,
+                FRAGMENT,
+                &ipv6_fragment(185, false, TCP, &[0, 53]),
+            ),
+            XDP_PASS,
+        ),
+    ] {
+        assert_eq!(test_run(program, &frame), action, "{frame:02x?}");
+    }
+}
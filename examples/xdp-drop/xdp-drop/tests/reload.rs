@@ -0,0 +1,135 @@
+//! Tests for reloading the blocklist from a file. These run against an
+//! in-memory [`PrefixMap`], so unlike the other tests they don't load any eBPF.
+
+use std::collections::BTreeSet;
+
+use aya::maps::MapError;
+use example_utils::prefix::Prefix;
+use xdp_drop::blocklist::{self, Changes, PrefixMap};
+
+#[derive(Debug, PartialEq, Eq)]
+enum Op {
+    Insert(Prefix),
+    Remove(Prefix),
+}
+
+/// Records every update so tests can check what `sync` touched and in which
+/// order.
+#[derive(Default)]
+struct FakeMap {
+    prefixes: BTreeSet<Prefix>,
+    ops: Vec<Op>,
+}
+
+impl PrefixMap for FakeMap {
+    fn prefixes(&self) -> Result<BTreeSet<Prefix>, MapError> {
+        Ok(self.prefixes.clone())
+    }
+
+    fn insert(&mut self, prefix: Prefix) -> Result<(), MapError> {
+        self.prefixes.insert(prefix);
+        self.ops.push(Op::Insert(prefix));
+        Ok(())
+    }
+
+    fn remove(&mut self, prefix: Prefix) -> Result<(), MapError> {
+        self.prefixes.remove(&prefix);
+        self.ops.push(Op::Remove(prefix));
+        Ok(())
+    }
+}
+
+fn prefix(s: &str) -> Prefix {
+    s.parse().unwrap()
+}
+
+fn prefixes(prefixes: &[&str]) -> BTreeSet<Prefix> {
+    prefixes.iter().copied().map(prefix).collect()
+}
+
+#[test]
+fn parse_skips_comments_and_blank_lines() {
+    let contents = "\
+# bogons
+10.0.0.0/8
+  192.0.2.7   # a single host
+
+2001:db8::/32
+";
+    assert_eq!(
+        blocklist::parse(contents).unwrap(),
+        prefixes(&["10.0.0.0/8", "192.0.2.7/32", "2001:db8::/32"]),
+    );
+}
+
+#[test]
+fn parse_clears_host_bits() {
+    assert_eq!(prefix("10.1.2.3/8"), prefix("10.0.0.0/8"));
+    assert_eq!(prefix("2001:db8::1/32").to_string(), "2001:db8::/32");
+}
+
+#[test]
+fn parse_reports_the_bad_line() {
+    for contents in ["10.0.0.0/8\n10.0.0.0/33\n", "10.0.0.0/8\nlocalhost\n"] {
+        let err = blocklist::parse(contents).unwrap_err();
+        assert_eq!(err.to_string(), "line 2", "{contents:?}");
+    }
+}
+
+#[test]
+fn sync_applies_only_the_difference() {
+    let mut map = FakeMap {
+        prefixes: prefixes(&["10.0.0.0/8", "192.0.2.0/24"]),
+        ..Default::default()
+    };
+
+    let changes = blocklist::sync(
+        &mut map,
+        &prefixes(&["10.0.0.0/8", "192.0.0.0/16", "2001:db8::/32"]),
+    )
+    .unwrap();
+
+    assert_eq!(
+        changes,
+        Changes {
+            added: vec![prefix("192.0.0.0/16"), prefix("2001:db8::/32")],
+            removed: vec![prefix("192.0.2.0/24")],
+        }
+    );
+    // The unchanged prefix is never touched, and the wider prefix goes in
+    // before the narrower one comes out.
+    assert_eq!(
+        map.ops,
+        [
+            Op::Insert(prefix("192.0.0.0/16")),
+            Op::Insert(prefix("2001:db8::/32")),
+            Op::Remove(prefix("192.0.2.0/24")),
+        ]
+    );
+}
+
+#[test]
+fn sync_is_idempotent() {
+    let mut map = FakeMap::default();
+    let desired = prefixes(&["10.0.0.0/8", "2001:db8::/32"]);
+
+    blocklist::sync(&mut map, &desired).unwrap();
+    map.ops.clear();
+    let changes = blocklist::sync(&mut map, &desired).unwrap();
+
+    assert_eq!(changes, Changes::default());
+    assert!(map.ops.is_empty());
+    assert_eq!(map.prefixes, desired);
+}
+
+#[test]
+fn sync_to_empty_removes_everything() {
+    let mut map = FakeMap {
+        prefixes: prefixes(&["10.0.0.0/8", "2001:db8::/32"]),
+        ..Default::default()
+    };
+
+    blocklist::sync(&mut map, &BTreeSet::new()).unwrap();
+
+    assert!(map.prefixes.is_empty());
+}
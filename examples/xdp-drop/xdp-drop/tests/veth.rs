@@ -0,0 +1,76 @@
+//! End-to-end tests that run `xdp-drop` on one end of a veth pair and send it
+//! frames from the other.
+
+mod common;
+
+use std::net::Ipv4Addr;
+
+use common::*;
+use example_test_utils::netns::{Netns, XDP_IFACE};
+
+const XDP_DROP: &str = env!("CARGO_BIN_EXE_xdp-drop");
+
+const BLOCKED: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+const ALLOWED: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+#[test]
+fn drops_blocked_sources() {
+    let netns = Netns::new();
+    let mut xdp_drop = netns.spawn(
+        XDP_DROP,
+        &[
+            "--iface",
+            XDP_IFACE,
+            "--block",
+            "198.51.100.0/24",
+            "--json",
+            "--stats-interval",
+            "1",
+        ],
+    );
+
+    netns.send(&ipv4_frame(BLOCKED, UDP, 53));
+    netns.send(&ipv4_frame(ALLOWED, UDP, 53));
+
+    let event = xdp_drop.wait_for(|line| line.starts_with('{'));
+    assert!(event.contains(r#""source":"198.51.100.7""#), "{event}");
+    assert!(event.contains(r#""action":"drop""#), "{event}");
+    assert!(event.contains(r#""reason":"blocklist""#), "{event}");
+    // The counters are cumulative, so they settle on the final totals.
+    xdp_drop.wait_for(|line| {
+        line.contains("passed: 1 packets")
+            && line.contains("dropped: 1 packets")
+    });
+    let mut output = xdp_drop.output().to_vec();
+    output.extend(xdp_drop.stop());
+    let allowed = format!(r#""source":"{ALLOWED}""#);
+    assert!(
+        !output.iter().any(|line| line.contains(&allowed)),
+        "{output:?}"
+    );
+}
+
+#[test]
+fn drops_sources_that_are_not_allowed() {
+    let netns = Netns::new();
+    let mut xdp_drop = netns.spawn(
+        XDP_DROP,
+        &[
+            "--iface",
+            XDP_IFACE,
+            "--mode",
+            "allow",
+            "--allow",
+            "203.0.113.0/24",
+            "--json",
+        ],
+    );
+
+    netns.send(&ipv4_frame(ALLOWED, UDP, 53));
+    netns.send(&ipv4_frame(BLOCKED, UDP, 53));
+
+    let event = xdp_drop.wait_for(|line| line.starts_with('{'));
+    assert!(event.contains(r#""source":"198.51.100.7""#), "{event}");
+    assert!(event.contains(r#""reason":"not-allowed""#), "{event}");
+    xdp_drop.stop();
+}
@@ -1,9 +1,22 @@
 #![no_std] // (1)
 #![no_main] // (2)
 
-use aya_ebpf::{bindings::xdp_action, macros::xdp, programs::XdpContext};
+use aya_ebpf::{
+    bindings::xdp_action,
+    macros::{map, xdp},
+    maps::{Array, PerCpuArray},
+    programs::XdpContext,
+};
 use aya_log_ebpf::info;
 
+/// The number of packets received, counted separately on each CPU.
+#[map] // (8)
+static PACKETS: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
+
+/// Set to 1 by user space to log every packet.
+#[map]
+static VERBOSE: Array<u32> = Array::with_max_entries(1, 0);
+
 #[xdp] // (4)
 pub fn xdp_hello(ctx: XdpContext) -> u32 {
     // (5)
@@ -15,7 +28,13 @@ pub fn xdp_hello(ctx: XdpContext) -> u32 {
 
 unsafe fn try_xdp_hello(ctx: XdpContext) -> Result<u32, u32> {
     // (6)
-    info!(&ctx, "received a packet");
+    if let Some(packets) = PACKETS.get_ptr_mut(0) {
+        unsafe { *packets += 1 };
+    }
+    // (9)
+    if VERBOSE.get(0) == Some(&1) {
+        info!(&ctx, "received a packet");
+    }
     // (7)
     Ok(xdp_action::XDP_PASS)
 }
@@ -1,14 +1,32 @@
-use anyhow::Context;
-use aya::programs::{Xdp, XdpMode};
+use std::time::Duration;
+
+use aya::{
+    maps::{Array, PerCpuArray},
+    programs::Xdp,
+};
 use aya_log::EbpfLogger;
 use clap::Parser;
+use example_utils::attach::{self, AttachMode};
 use log::{info, warn};
-use tokio::signal; // (1)
+use tokio::{
+    signal, // (1)
+    time::{self, Instant},
+};
 
 #[derive(Debug, Parser)]
 struct Opt {
-    #[clap(short, long, default_value = "eth0")]
-    iface: String, // (2)
+    /// Interface to attach to. May be given multiple times.
+    #[clap(short, long, default_value = "eth0", conflicts_with = "all")]
+    iface: Vec<String>, // (2)
+    /// Attach to every interface other than loopback.
+    #[clap(long)]
+    all: bool,
+    /// How to attach the program.
+    #[clap(long, value_enum, default_value_t = AttachMode::Auto)]
+    mode: AttachMode,
+    /// Log every packet as well as the packet rate.
+    #[clap(short, long)]
+    verbose: bool,
 }
 
 #[tokio::main] // (3)
@@ -50,13 +68,42 @@ async fn main() -> Result<(), anyhow::Error> {
     let program: &mut Xdp = bpf.program_mut("xdp_hello").unwrap().try_into()?;
     program.load()?; // (7)
     // (8)
-    program.attach(&opt.iface, XdpMode::default())
-        .context("failed to attach the XDP program with default mode - try changing XdpMode::default() to XdpMode::Skb")?;
+    let ifaces = attach::interfaces(&opt.iface, opt.all)?;
+    let attachments = attach::attach_all(program, &ifaces, opt.mode)?;
+
+    if opt.verbose {
+        let mut verbose: Array<_, u32> =
+            Array::try_from(bpf.map_mut("VERBOSE").unwrap())?;
+        verbose.set(0, 1, 0)?;
+    }
+
+    // (10)
+    let packets: PerCpuArray<_, u64> =
+        PerCpuArray::try_from(bpf.take_map("PACKETS").unwrap())?;
+    let period = Duration::from_secs(1);
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    let mut previous = 0;
 
     let ctrl_c = signal::ctrl_c();
+    tokio::pin!(ctrl_c);
     info!("Waiting for Ctrl-C...");
-    ctrl_c.await?;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                // (11)
+                let total: u64 = packets.get(&0, 0)?.iter().sum();
+                info!("{} packets/s", total - previous);
+                previous = total;
+            }
+            res = &mut ctrl_c => {
+                res?;
+                break;
+            }
+        }
+    }
     info!("Exiting...");
+    let program: &mut Xdp = bpf.program_mut("xdp_hello").unwrap().try_into()?;
+    attach::detach_all(program, attachments); // (9)
 
     Ok(())
 }
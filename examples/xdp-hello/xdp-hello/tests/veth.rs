@@ -0,0 +1,88 @@
+//! End-to-end tests that run `xdp-hello` on one end of a veth pair and send it
+//! frames from the other.
+
+use example_test_utils::netns::{Netns, Running, XDP_IFACE};
+
+const XDP_HELLO: &str = env!("CARGO_BIN_EXE_xdp-hello");
+
+/// A broadcast frame with the EtherType reserved for local experiments, which
+/// nothing in the kernel handles.
+fn frame() -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&[0xff; 6]); // destination MAC
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]); // source MAC
+    frame.extend_from_slice(&0x88b5u16.to_be_bytes());
+    frame.extend_from_slice(&[0; 46]);
+    frame
+}
+
+/// Adds up the per-second packet counts until they reach `expected`, and
+/// returns the total.
+fn count_packets(xdp_hello: &mut Running, expected: u64) -> u64 {
+    let mut total = 0;
+    while total < expected {
+        let line = xdp_hello.wait_for(|line| line.ends_with(" packets/s"));
+        let (_, message) = line.rsplit_once("] ").unwrap();
+        total += message
+            .strip_suffix(" packets/s")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+    }
+    total
+}
+
+#[test]
+fn counts_packets() {
+    let netns = Netns::new();
+    let mut xdp_hello = netns.spawn(XDP_HELLO, &["--iface", XDP_IFACE]);
+
+    for _ in 0..5 {
+        netns.send(&frame());
+    }
+
+    assert_eq!(count_packets(&mut xdp_hello, 5), 5);
+    let rest = xdp_hello.stop();
+    assert!(
+        rest.iter()
+            .any(|line| line.contains("detached from veth-xdp")),
+        "{rest:?}"
+    );
+}
+
+#[test]
+fn logs_each_packet_when_verbose() {
+    let netns = Netns::new();
+    let mut xdp_hello =
+        netns.spawn(XDP_HELLO, &["--iface", XDP_IFACE, "--verbose"]);
+
+    for _ in 0..3 {
+        netns.send(&frame());
+    }
+
+    for _ in 0..3 {
+        xdp_hello.wait_for(|line| line.ends_with("received a packet"));
+    }
+    xdp_hello.stop();
+}
+
+#[test]
+fn attaches_in_the_mode_asked_for() {
+    // veth supports driver mode, so that's what auto picks.
+    for (mode, name) in [("auto", "driver"), ("skb", "SKB")] {
+        let netns = Netns::new();
+        let xdp_hello =
+            netns.spawn(XDP_HELLO, &["--iface", XDP_IFACE, "--mode", mode]);
+
+        let attached = format!("attached to veth-xdp in {name} mode");
+        assert!(
+            xdp_hello
+                .output()
+                .iter()
+                .any(|line| line.ends_with(&attached)),
+            "{:?}",
+            xdp_hello.output()
+        );
+        xdp_hello.stop();
+    }
+}
@@ -1,11 +1,138 @@
 #![no_std]
 
+/// A packet seen by `xdp_firewall`, sent to user space through the `EVENTS`
+/// ring buffer.
+///
+/// The struct has no padding and every field is always written, so that the
+/// whole record is initialized when the verifier checks the ring buffer
+/// output.
 #[repr(C)]
-#[derive(Clone, Copy)]
-pub struct PacketLog {
-    pub ipv4_address: u32,
-    pub action: u32,
+#[derive(Clone, Copy, Default)]
+pub struct FlowRecord {
+    pub src_mac: [u8; 6],
+    pub dst_mac: [u8; 6],
+    /// Source and destination addresses. IPv4 addresses are IPv4-mapped
+    /// (`::ffff:a.b.c.d`).
+    pub src_addr: [u8; 16],
+    pub dst_addr: [u8; 16],
+    /// Length of the whole frame.
+    pub len: u32,
+    /// TCP or UDP ports, 0 for other protocols.
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// IDs of the VLAN tags the frame carried, outermost first. Only the first
+    /// `vlan_count` are set.
+    pub vlan_ids: [u16; 2],
+    /// 4 or 6.
+    pub ip_version: u8,
+    /// The IP protocol number of the transport header.
+    pub proto: u8,
+    /// The TCP flags byte, 0 for other protocols.
+    pub tcp_flags: u8,
+    pub ttl: u8,
+    /// ICMP type and code, 0 for other protocols.
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    pub vlan_count: u8,
+    /// Whether the packet is an IPv4 fragment or carries an IPv6 fragment
+    /// header, one of the [`fragment`] constants.
+    pub fragment: u8,
 }
 
+/// Values of [`FlowRecord::fragment`].
+pub mod fragment {
+    /// A whole packet.
+    pub const NONE: u8 = 0;
+    /// The first fragment of a packet, which holds the transport header.
+    pub const FIRST: u8 = 1;
+    /// Any other fragment. These carry no transport header, so the record has
+    /// no ports, TCP flags or ICMP type.
+    pub const LATER: u8 = 2;
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowRecord {} // (1)
+
+/// What `xdp_firewall` does with each `FlowRecord`, the single entry of the
+/// `MODE` map.
+pub mod mode {
+    /// Send it to user space through `EVENTS`.
+    pub const STREAM: u32 = 0;
+    /// Add it to its flow's counts in `FLOWS`.
+    pub const TOP_TALKERS: u32 = 1;
+}
+
+/// Key of the `FLOWS` map: the addresses, ports and protocol of a
+/// [`FlowRecord`]. Flows are one-way, so the two directions of a connection
+/// are counted separately.
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src_addr: [u8; 16],
+    pub dst_addr: [u8; 16],
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub proto: u8,
+    pub ip_version: u8,
+    /// Always zero, as the kernel hashes the padding along with the rest.
+    _pad: [u8; 2],
+}
+
+impl From<&FlowRecord> for FlowKey {
+    fn from(record: &FlowRecord) -> Self {
+        Self {
+            src_addr: record.src_addr,
+            dst_addr: record.dst_addr,
+            src_port: record.src_port,
+            dst_port: record.dst_port,
+            proto: record.proto,
+            ip_version: record.ip_version,
+            _pad: [0; 2],
+        }
+    }
+}
+
+/// Value of the `FLOWS` map.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct FlowStats {
+    pub packets: u64,
+    /// Frame lengths, summed.
+    pub bytes: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowKey {}
+
 #[cfg(feature = "user")]
-unsafe impl aya::Pod for PacketLog {} // (1)
+unsafe impl aya::Pod for FlowStats {}
+
+/// How `xdp_firewall` samples packets into the `SAMPLES` perf buffers, the
+/// single entry of the `SAMPLING` map.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Sampling {
+    /// Sample one packet in every `rate`, or none if 0.
+    pub rate: u32,
+    /// How many bytes of each sampled packet to copy.
+    pub snaplen: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Sampling {}
+
+/// The start of a `SAMPLES` perf event. The first `cap_len` bytes of the
+/// packet follow it.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct PacketSample {
+    /// Length of the whole frame.
+    pub len: u32,
+    /// How many bytes of the frame were copied, at most the snap length.
+    pub cap_len: u32,
+}
+
+/// Maps an IPv4 address into the IPv6 address space, as `::ffff:a.b.c.d`.
+pub fn ipv4_mapped([a, b, c, d]: [u8; 4]) -> [u8; 16] {
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d]
+}
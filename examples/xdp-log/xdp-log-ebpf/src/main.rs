@@ -1,16 +1,25 @@
 #![no_std]
 #![no_main]
 
-use aya_ebpf::{bindings::xdp_action, macros::xdp, programs::XdpContext};
-use aya_log_ebpf::info;
+use aya_ebpf::{
+    bindings::xdp_action,
+    macros::{map, xdp},
+    maps::{Array, LruPerCpuHashMap, PerCpuArray, PerfEventArray, RingBuf},
+    programs::XdpContext,
+};
+use aya_log_ebpf::debug;
 
 use core::mem;
 use network_types::{
-    eth::{EthHdr, EtherType},
-    ip::{IpError, IpProto, Ipv4Hdr},
+    eth::EthHdr,
+    ip::{IpError, IpProto, Ipv4Hdr, Ipv6Hdr},
     tcp::TcpHdr,
     udp::UdpHdr,
 };
+use xdp_log_common::{
+    FlowKey, FlowRecord, FlowStats, PacketSample, Sampling, fragment,
+    ipv4_mapped, mode,
+};
 
 #[cfg(not(test))]
 #[panic_handler]
@@ -18,6 +27,33 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
     loop {}
 }
 
+/// A `FlowRecord` for every IPv4 and IPv6 packet. When user space falls behind
+/// and the buffer fills up, further records are lost.
+#[map]
+static EVENTS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+/// One of the `mode` constants, set by user space.
+#[map]
+static MODE: Array<u32> = Array::with_max_entries(1, 0);
+
+/// Packet and byte counts of each flow in top talkers mode. When the map is
+/// full, the least recently updated flow makes way for a new one.
+#[map]
+static FLOWS: LruPerCpuHashMap<FlowKey, FlowStats> =
+    LruPerCpuHashMap::with_max_entries(16384, 0);
+
+/// Set by user space to turn on sampling.
+#[map]
+static SAMPLING: Array<Sampling> = Array::with_max_entries(1, 0);
+
+/// Packets seen since the last sample, on each CPU.
+#[map]
+static SAMPLE_COUNT: PerCpuArray<u32> = PerCpuArray::with_max_entries(1, 0);
+
+/// A `PacketSample` and the start of the packet for every sampled packet.
+#[map]
+static SAMPLES: PerfEventArray<PacketSample> = PerfEventArray::new(0);
+
 #[xdp]
 pub fn xdp_firewall(ctx: XdpContext) -> u32 {
     match try_xdp_firewall(ctx) {
@@ -39,35 +75,315 @@ fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
     Ok((start + offset) as *const T)
 }
 
-fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
-    let ethhdr: *const EthHdr = ptr_at(&ctx, 0)?; // (2)
-    match unsafe { (*ethhdr).ether_type() } {
-        Ok(EtherType::Ipv4) => {}
-        _ => return Ok(xdp_action::XDP_PASS),
+// EtherTypes, in host byte order.
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+/// An 802.1Q VLAN tag.
+const ETHERTYPE_VLAN: u16 = 0x8100;
+/// An 802.1ad service tag, the outer tag of a QinQ frame.
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+/// An 802.1Q or 802.1ad tag, which sits between the MAC addresses and the
+/// EtherType of the frame. It holds the priority and VLAN ID, then the
+/// EtherType of whatever follows.
+#[repr(C)]
+struct VlanTag {
+    tci: [u8; 2],
+    ether_type: [u8; 2],
+}
+
+const VLAN_ID_MASK: u16 = 0x0fff;
+
+/// Reads the EtherType of the frame, skipping up to two VLAN tags and recording
+/// their IDs. Returns the EtherType of the payload and its offset.
+///
+/// A frame with more tags is returned with a VLAN EtherType, so it's passed
+/// without being parsed any further.
+fn parse_vlan_tags(
+    ctx: &XdpContext,
+    record: &mut FlowRecord,
+) -> Result<(u16, usize), ()> {
+    let ether_type: *const [u8; 2] = ptr_at(ctx, EthHdr::LEN - 2)?;
+    let mut ether_type = u16::from_be_bytes(unsafe { *ether_type });
+    let mut offset = EthHdr::LEN;
+    // Iterating over the array rather than indexing it keeps the loop
+    // bounded, which the verifier needs to see.
+    for vlan_id in &mut record.vlan_ids {
+        if !matches!(ether_type, ETHERTYPE_VLAN | ETHERTYPE_QINQ) {
+            break;
+        }
+        let tag: *const VlanTag = ptr_at(ctx, offset)?;
+        *vlan_id = u16::from_be_bytes(unsafe { (*tag).tci }) & VLAN_ID_MASK;
+        record.vlan_count += 1;
+        ether_type = u16::from_be_bytes(unsafe { (*tag).ether_type });
+        offset += mem::size_of::<VlanTag>();
     }
+    Ok((ether_type, offset))
+}
+
+/// The IHL field, the low nibble of the first byte of the IPv4 header, is the
+/// header length in 32-bit words. It's at least 5 and at most 15, i.e. up to 40
+/// bytes of options.
+const IPV4_IHL_MASK: u8 = 0x0f;
+const IPV4_MAX_LEN: usize = 60;
+/// The flags and fragment offset share the 7th and 8th bytes of the header.
+const IPV4_FRAG_OFFSET: usize = 6;
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_FRAG_OFFSET_MASK: u16 = 0x1fff;
+
+/// Reads the length of the IPv4 header at `offset`, options included.
+fn ipv4_header_len(ctx: &XdpContext, offset: usize) -> Result<usize, ()> {
+    let vihl: *const u8 = ptr_at(ctx, offset)?;
+    let len = usize::from(unsafe { *vihl } & IPV4_IHL_MASK) * 4;
+    // Malformed headers are rejected. This also tells the verifier that the
+    // transport header offset is bounded.
+    if !(Ipv4Hdr::LEN..=IPV4_MAX_LEN).contains(&len) {
+        return Err(());
+    }
+    Ok(len)
+}
+
+/// Classifies the IPv4 header at `offset` as one of the `fragment` values.
+fn ipv4_fragment(ctx: &XdpContext, offset: usize) -> Result<u8, ()> {
+    let field: *const [u8; 2] = ptr_at(ctx, offset + IPV4_FRAG_OFFSET)?;
+    let field = u16::from_be_bytes(unsafe { *field });
+    Ok(if field & IPV4_FRAG_OFFSET_MASK != 0 {
+        fragment::LATER
+    } else if field & IPV4_MORE_FRAGMENTS != 0 {
+        fragment::FIRST
+    } else {
+        fragment::NONE
+    })
+}
 
-    let ipv4hdr: *const Ipv4Hdr = ptr_at(&ctx, EthHdr::LEN)?;
-    let source_addr = u32::from_be_bytes(unsafe { (*ipv4hdr).src_addr });
+/// Parses the IPv4 header at `offset` into `record`, returning the offset of
+/// the transport header.
+fn parse_ipv4(
+    ctx: &XdpContext,
+    offset: usize,
+    record: &mut FlowRecord,
+) -> Result<usize, ()> {
+    let ipv4hdr: *const Ipv4Hdr = ptr_at(ctx, offset)?;
+    record.ip_version = 4;
+    record.src_addr = ipv4_mapped(unsafe { (*ipv4hdr).src_addr });
+    record.dst_addr = ipv4_mapped(unsafe { (*ipv4hdr).dst_addr });
+    record.ttl = unsafe { (*ipv4hdr).ttl };
+    record.proto = proto_number(unsafe { (*ipv4hdr).proto() });
+    record.fragment = ipv4_fragment(ctx, offset)?;
+    Ok(offset + ipv4_header_len(ctx, offset)?)
+}
+
+// IPv6 extension headers that may precede the transport header.
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_DESTINATION_OPTIONS: u8 = 60;
+
+/// How many extension headers we walk before giving up. Each type should occur
+/// at most once, except destination options which may occur twice.
+const MAX_IPV6_EXT_HEADERS: usize = 8;
+
+/// The start of a hop-by-hop, routing or destination options header. `len` is
+/// the length of the header in 8-byte units, not counting the first 8 bytes.
+#[repr(C)]
+struct Ipv6ExtHdr {
+    next_hdr: u8,
+    len: u8,
+}
+
+#[repr(C)]
+struct Ipv6FragHdr {
+    next_hdr: u8,
+    _reserved: u8,
+    /// The fragment offset in 8-byte units, in the top 13 bits, and the "more
+    /// fragments" flag in the lowest bit.
+    offset_flags: [u8; 2],
+    _identification: [u8; 4],
+}
 
-    let proto = unsafe { (*ipv4hdr).proto() }
-        .map_err(|IpError::InvalidProto(_proto)| ())?;
+const IPV6_FRAG_OFFSET_MASK: u16 = 0xfff8;
+const IPV6_MORE_FRAGMENTS: u16 = 0x0001;
 
-    let source_port = match proto {
-        IpProto::Tcp => {
-            let tcphdr: *const TcpHdr =
-                ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
-            u16::from_be_bytes(unsafe { (*tcphdr).source })
+/// Parses the IPv6 header at `offset` and any extension headers after it into
+/// `record`, returning the offset of the transport header.
+///
+/// A packet with more than `MAX_IPV6_EXT_HEADERS` extension headers is recorded
+/// with the next header it didn't get to as its protocol.
+fn parse_ipv6(
+    ctx: &XdpContext,
+    offset: usize,
+    record: &mut FlowRecord,
+) -> Result<usize, ()> {
+    let ipv6hdr: *const Ipv6Hdr = ptr_at(ctx, offset)?;
+    record.ip_version = 6;
+    record.src_addr = unsafe { (*ipv6hdr).src_addr };
+    record.dst_addr = unsafe { (*ipv6hdr).dst_addr };
+    record.ttl = unsafe { (*ipv6hdr).hop_limit };
+    let mut next_hdr = proto_number(unsafe { (*ipv6hdr).next_hdr() });
+    let mut offset = offset + Ipv6Hdr::LEN;
+    // The verifier only accepts loops it can prove terminate, hence the fixed
+    // number of iterations.
+    for _ in 0..MAX_IPV6_EXT_HEADERS {
+        match next_hdr {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION_OPTIONS => {
+                let hdr: *const Ipv6ExtHdr = ptr_at(ctx, offset)?;
+                next_hdr = unsafe { (*hdr).next_hdr };
+                offset += (usize::from(unsafe { (*hdr).len }) + 1) * 8;
+            }
+            IPV6_FRAGMENT => {
+                let hdr: *const Ipv6FragHdr = ptr_at(ctx, offset)?;
+                let field = u16::from_be_bytes(unsafe { (*hdr).offset_flags });
+                next_hdr = unsafe { (*hdr).next_hdr };
+                offset += mem::size_of::<Ipv6FragHdr>();
+                if field & IPV6_FRAG_OFFSET_MASK != 0 {
+                    // The rest of the headers are in the first fragment.
+                    record.fragment = fragment::LATER;
+                    break;
+                } else if field & IPV6_MORE_FRAGMENTS != 0 {
+                    record.fragment = fragment::FIRST;
+                }
+            }
+            _ => break,
         }
-        IpProto::Udp => {
-            let udphdr: *const UdpHdr =
-                ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
-            unsafe { (*udphdr).src_port() }
+    }
+    record.proto = next_hdr;
+    Ok(offset)
+}
+
+/// The protocol number of a `proto()` or `next_hdr()` header field, including
+/// numbers that `IpProto` doesn't know about.
+fn proto_number(proto: Result<IpProto, IpError>) -> u8 {
+    match proto {
+        Ok(proto) => proto as u8,
+        Err(IpError::InvalidProto(proto)) => proto,
+    }
+}
+
+const TCP: u8 = IpProto::Tcp as u8;
+const UDP: u8 = IpProto::Udp as u8;
+const ICMP: u8 = IpProto::Icmp as u8;
+const ICMPV6: u8 = IpProto::Ipv6Icmp as u8;
+
+/// TCP flags are the 14th byte of the header.
+const TCP_FLAGS_OFFSET: usize = 13;
+
+/// Fills in the transport fields of `record` from the `record.proto` header at
+/// `offset`. Protocols other than TCP, UDP, ICMP and ICMPv6, and fragments that
+/// don't start with the transport header, are recorded by protocol number only.
+fn parse_transport(
+    ctx: &XdpContext,
+    offset: usize,
+    record: &mut FlowRecord,
+) -> Result<(), ()> {
+    if record.fragment == fragment::LATER {
+        return Ok(());
+    }
+    match record.proto {
+        TCP => {
+            let tcphdr: *const TcpHdr = ptr_at(ctx, offset)?;
+            let flags: *const u8 = ptr_at(ctx, offset + TCP_FLAGS_OFFSET)?;
+            record.src_port = u16::from_be_bytes(unsafe { (*tcphdr).source });
+            record.dst_port = u16::from_be_bytes(unsafe { (*tcphdr).dest });
+            record.tcp_flags = unsafe { *flags };
+        }
+        UDP => {
+            let udphdr: *const UdpHdr = ptr_at(ctx, offset)?;
+            record.src_port = unsafe { (*udphdr).src_port() };
+            record.dst_port = unsafe { (*udphdr).dst_port() };
         }
-        _ => return Err(()),
+        ICMP | ICMPV6 => {
+            // Type and code are the first two bytes of every ICMP message.
+            let icmp: *const [u8; 2] = ptr_at(ctx, offset)?;
+            let [icmp_type, icmp_code] = unsafe { *icmp };
+            record.icmp_type = icmp_type;
+            record.icmp_code = icmp_code;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Copies the start of every `rate`th packet to `SAMPLES`, counting packets on
+/// each CPU separately so that CPUs don't contend for a shared counter.
+fn sample(ctx: &XdpContext) {
+    let Some(&Sampling { rate, snaplen }) = SAMPLING.get(0) else {
+        return;
+    };
+    let Some(count) = SAMPLE_COUNT.get_ptr_mut(0) else {
+        return;
     };
+    if rate == 0 {
+        return;
+    }
+    let count = unsafe { &mut *count };
+    *count += 1;
+    if *count < rate {
+        return;
+    }
+    *count = 0;
+    let len = (ctx.data_end() - ctx.data()) as u32;
+    let cap_len = len.min(snaplen);
+    // `output` passes these flags in the upper half of the helper's flags,
+    // which for XDP programs is how many bytes of the packet to append to the
+    // event, as `bpf_xdp_output` does.
+    SAMPLES.output(ctx, &PacketSample { len, cap_len }, cap_len);
+}
+
+/// Adds the packet described by `record` to its flow's counts.
+fn count_flow(record: &FlowRecord) {
+    let key = FlowKey::from(record);
+    let Some(stats) = FLOWS.get_ptr_mut(&key) else {
+        let stats = FlowStats {
+            packets: 1,
+            bytes: u64::from(record.len),
+        };
+        let _ = FLOWS.insert(&key, &stats, 0);
+        return;
+    };
+    // The map is per-CPU, so nothing else is updating this entry.
+    let stats = unsafe { &mut *stats };
+    stats.packets += 1;
+    stats.bytes += u64::from(record.len);
+}
+
+fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
+    sample(&ctx);
+    let ethhdr: *const EthHdr = ptr_at(&ctx, 0)?; // (2)
+    let mut record = FlowRecord {
+        src_mac: unsafe { (*ethhdr).src_addr },
+        dst_mac: unsafe { (*ethhdr).dst_addr },
+        len: (ctx.data_end() - ctx.data()) as u32,
+        ..Default::default()
+    };
+    let (ether_type, offset) = parse_vlan_tags(&ctx, &mut record)?;
+    let offset = match ether_type {
+        ETHERTYPE_IPV4 => parse_ipv4(&ctx, offset, &mut record)?,
+        ETHERTYPE_IPV6 => parse_ipv6(&ctx, offset, &mut record)?,
+        _ => return Ok(xdp_action::XDP_PASS),
+    };
+    parse_transport(&ctx, offset, &mut record)?;
 
     // (3)
-    info!(&ctx, "SRC IP: {:i}, SRC PORT: {}", source_addr, source_port);
+    if record.ip_version == 4 {
+        let [.., a, b, c, d] = record.src_addr;
+        debug!(
+            &ctx,
+            "SRC IP: {:i}, SRC PORT: {}",
+            u32::from_be_bytes([a, b, c, d]),
+            record.src_port
+        );
+    } else {
+        debug!(
+            &ctx,
+            "SRC IP: {:i}, SRC PORT: {}", record.src_addr, record.src_port
+        );
+    }
+    match MODE.get(0) {
+        Some(&mode::TOP_TALKERS) => count_flow(&record),
+        _ => {
+            let _ = EVENTS.output(&record, 0);
+        }
+    }
 
     Ok(xdp_action::XDP_PASS)
 }
@@ -0,0 +1,262 @@
+//! Flow records read from the `EVENTS` ring buffer, and how they're printed.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+};
+
+use xdp_log_common::{FlowRecord, fragment};
+
+const ICMP: u8 = 1;
+pub(crate) const TCP: u8 = 6;
+pub(crate) const UDP: u8 = 17;
+const ICMPV6: u8 = 58;
+
+/// TCP flag bits, in the order tcpdump prints them.
+const TCP_FLAGS: [(u8, &str); 8] = [
+    (0x02, "SYN"),
+    (0x01, "FIN"),
+    (0x04, "RST"),
+    (0x08, "PSH"),
+    (0x10, "ACK"),
+    (0x20, "URG"),
+    (0x40, "ECE"),
+    (0x80, "CWR"),
+];
+
+/// A [`FlowRecord`] decoded for display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flow {
+    pub src_mac: [u8; 6],
+    pub dst_mac: [u8; 6],
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub proto: u8,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub tcp_flags: u8,
+    pub ttl: u8,
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    pub len: u32,
+    vlan_ids: [u16; 2],
+    vlan_count: u8,
+    pub fragment: u8,
+}
+
+impl Flow {
+    /// Decodes a ring buffer record, which must be exactly one `FlowRecord`.
+    pub fn parse(record: &[u8]) -> Option<Self> {
+        if record.len() != size_of::<FlowRecord>() {
+            return None;
+        }
+        // SAFETY: the length was checked above and FlowRecord is Pod.
+        let record: FlowRecord =
+            unsafe { record.as_ptr().cast::<FlowRecord>().read_unaligned() };
+        Some(Self::from(record))
+    }
+
+    /// Whether the packet starts with its transport header, i.e. isn't a
+    /// fragment other than the first.
+    fn has_transport_header(&self) -> bool {
+        self.fragment != fragment::LATER
+    }
+
+    fn has_ports(&self) -> bool {
+        self.has_transport_header() && matches!(self.proto, TCP | UDP)
+    }
+
+    fn has_tcp_flags(&self) -> bool {
+        self.has_transport_header() && self.proto == TCP
+    }
+
+    fn is_icmp(&self) -> bool {
+        self.has_transport_header() && matches!(self.proto, ICMP | ICMPV6)
+    }
+
+    /// How the packet was fragmented, if it was.
+    fn fragment_name(&self) -> Option<&'static str> {
+        match self.fragment {
+            fragment::FIRST => Some("first"),
+            fragment::LATER => Some("later"),
+            _ => None,
+        }
+    }
+
+    /// The source address, with the port for TCP and UDP.
+    pub fn source(&self) -> Endpoint {
+        Endpoint(self.src, self.has_ports().then_some(self.src_port))
+    }
+
+    /// The destination address, with the port for TCP and UDP.
+    pub fn destination(&self) -> Endpoint {
+        Endpoint(self.dst, self.has_ports().then_some(self.dst_port))
+    }
+
+    /// The IDs of the frame's VLAN tags, outermost first.
+    pub fn vlan_ids(&self) -> &[u16] {
+        let count = usize::from(self.vlan_count).min(self.vlan_ids.len());
+        &self.vlan_ids[..count]
+    }
+
+    /// The names of the TCP flags that are set.
+    pub fn tcp_flags(&self) -> Vec<&'static str> {
+        TCP_FLAGS
+            .iter()
+            .filter(|&&(bit, _)| self.tcp_flags & bit != 0)
+            .map(|&(_, name)| name)
+            .collect()
+    }
+
+    /// The column headings matching [`Flow::table_row`].
+    pub fn table_header() -> String {
+        format!(
+            "{:<17} {:<17} {:<9} {:<21} {:<21} {:<6} {:>3} {:>5}  INFO",
+            "SRC MAC",
+            "DST MAC",
+            "VLAN",
+            "SOURCE",
+            "DESTINATION",
+            "PROTO",
+            "TTL",
+            "LEN"
+        )
+    }
+
+    /// Formats the flow as a line of a table, under [`Flow::table_header`].
+    pub fn table_row(&self) -> String {
+        let mut info = Vec::new();
+        if self.has_tcp_flags() {
+            info.push(self.tcp_flags().join(","));
+        } else if self.is_icmp() {
+            info.push(format!(
+                "type {} code {}",
+                self.icmp_type, self.icmp_code
+            ));
+        }
+        if let Some(fragment) = self.fragment_name() {
+            info.push(format!("{fragment} fragment"));
+        }
+        let info = info.join(" ");
+        // Outermost first, e.g. 100.20 for a QinQ frame.
+        let vlans: Vec<_> =
+            self.vlan_ids().iter().map(u16::to_string).collect();
+        format!(
+            "{:<17} {:<17} {:<9} {:<21} {:<21} {:<6} {:>3} {:>5}  {info}",
+            Mac(self.src_mac).to_string(),
+            Mac(self.dst_mac).to_string(),
+            vlans.join("."),
+            self.source().to_string(),
+            self.destination().to_string(),
+            Protocol(self.proto).to_string(),
+            self.ttl,
+            self.len,
+        )
+    }
+
+    /// Formats the flow as a single line of JSON. Fields that don't apply to
+    /// the protocol are left out.
+    ///
+    /// Every value is a number, an address or a fixed name, so nothing needs
+    /// escaping.
+    pub fn to_json(&self) -> String {
+        let mut fields = vec![
+            format!(r#""src_mac":"{}""#, Mac(self.src_mac)),
+            format!(r#""dst_mac":"{}""#, Mac(self.dst_mac)),
+        ];
+        if !self.vlan_ids().is_empty() {
+            let ids: Vec<_> =
+                self.vlan_ids().iter().map(u16::to_string).collect();
+            fields.push(format!(r#""vlan_ids":[{}]"#, ids.join(",")));
+        }
+        fields.extend([
+            format!(r#""src_ip":"{}""#, self.src),
+            format!(r#""dst_ip":"{}""#, self.dst),
+            format!(r#""proto":"{}""#, Protocol(self.proto)),
+        ]);
+        if self.has_ports() {
+            fields.push(format!(r#""src_port":{}"#, self.src_port));
+            fields.push(format!(r#""dst_port":{}"#, self.dst_port));
+        }
+        if self.has_tcp_flags() {
+            let flags: Vec<_> = self
+                .tcp_flags()
+                .iter()
+                .map(|flag| format!(r#""{flag}""#))
+                .collect();
+            fields.push(format!(r#""tcp_flags":[{}]"#, flags.join(",")));
+        }
+        if self.is_icmp() {
+            fields.push(format!(r#""icmp_type":{}"#, self.icmp_type));
+            fields.push(format!(r#""icmp_code":{}"#, self.icmp_code));
+        }
+        if let Some(fragment) = self.fragment_name() {
+            fields.push(format!(r#""fragment":"{fragment}""#));
+        }
+        fields.push(format!(r#""ttl":{}"#, self.ttl));
+        fields.push(format!(r#""len":{}"#, self.len));
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+impl From<FlowRecord> for Flow {
+    fn from(record: FlowRecord) -> Self {
+        let addr = |addr| match record.ip_version {
+            4 => Ipv6Addr::from(addr).to_canonical(),
+            _ => IpAddr::V6(Ipv6Addr::from(addr)),
+        };
+        Self {
+            src_mac: record.src_mac,
+            dst_mac: record.dst_mac,
+            src: addr(record.src_addr),
+            dst: addr(record.dst_addr),
+            proto: record.proto,
+            src_port: record.src_port,
+            dst_port: record.dst_port,
+            tcp_flags: record.tcp_flags,
+            ttl: record.ttl,
+            icmp_type: record.icmp_type,
+            icmp_code: record.icmp_code,
+            len: record.len,
+            vlan_ids: record.vlan_ids,
+            vlan_count: record.vlan_count,
+            fragment: record.fragment,
+        }
+    }
+}
+
+/// An address, and a port if the protocol has them.
+pub struct Endpoint(pub(crate) IpAddr, pub(crate) Option<u16>);
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self(addr, Some(port)) => SocketAddr::new(addr, port).fmt(f),
+            Self(addr, None) => addr.fmt(f),
+        }
+    }
+}
+
+struct Mac([u8; 6]);
+
+impl fmt::Display for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// An IP protocol number, shown by name when it's one we parse.
+pub(crate) struct Protocol(pub(crate) u8);
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            ICMP => f.write_str("icmp"),
+            TCP => f.write_str("tcp"),
+            UDP => f.write_str("udp"),
+            ICMPV6 => f.write_str("icmpv6"),
+            proto => write!(f, "{proto}"),
+        }
+    }
+}
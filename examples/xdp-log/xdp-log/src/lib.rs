@@ -0,0 +1,3 @@
+pub mod flow;
+pub mod pcap;
+pub mod top;
@@ -1,14 +1,182 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
 use anyhow::Context;
-use aya::programs::{Xdp, XdpMode};
+use aya::{
+    maps::{Array, MapData, PerCpuHashMap, PerfEventArray, RingBuf},
+    programs::Xdp,
+    util::online_cpus,
+};
 use aya_log::EbpfLogger;
-use clap::Parser;
-use log::{info, warn};
-use tokio::signal;
+use bytes::BytesMut;
+use clap::{Parser, ValueEnum};
+use example_utils::attach::{self, AttachMode};
+use log::{error, info, warn};
+use tokio::{
+    io::{Interest, unix::AsyncFd},
+    signal,
+    time::{self, Instant},
+};
+use xdp_log::{
+    flow::Flow,
+    pcap::{PcapWriter, Sample},
+    top::{self, Talker},
+};
+use xdp_log_common::{FlowKey, FlowStats, PacketSample, Sampling, mode};
 
 #[derive(Debug, Parser)]
 struct Opt {
-    #[clap(short, long, default_value = "eth0")]
-    iface: String,
+    /// Interface to attach to. May be given multiple times.
+    #[clap(short, long, default_value = "eth0", conflicts_with = "all")]
+    iface: Vec<String>,
+    /// Attach to every interface other than loopback.
+    #[clap(long)]
+    all: bool,
+    /// How to attach the program.
+    #[clap(long, value_enum, default_value_t = AttachMode::Auto)]
+    mode: AttachMode,
+    /// How to print each packet.
+    #[clap(long, value_enum, default_value_t = Format::Table)]
+    format: Format,
+    /// Instead of printing every packet, print the N flows that sent the most
+    /// bytes every interval.
+    #[clap(long, value_name = "N", conflicts_with = "format")]
+    top: Option<usize>,
+    /// Seconds between top talkers reports.
+    #[clap(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "top"
+    )]
+    interval: u64,
+    /// Also write sampled packets to this pcap file.
+    #[clap(long)]
+    pcap: Option<PathBuf>,
+    /// Sample one packet in every N.
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "pcap"
+    )]
+    sample_rate: u32,
+    /// How many bytes of each sampled packet to keep.
+    #[clap(
+        long,
+        default_value_t = 256,
+        value_parser = clap::value_parser!(u32).range(1..=65535),
+        requires = "pcap"
+    )]
+    snaplen: u32,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Format {
+    /// One aligned line per packet, under a header.
+    Table,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Prints the flow records in `EVENTS` as they arrive, until the program
+/// exits.
+fn spawn_flow_printer(
+    ring: RingBuf<MapData>,
+    format: Format,
+) -> anyhow::Result<()> {
+    let mut ring = AsyncFd::with_interest(ring, Interest::READABLE)?;
+    if let Format::Table = format {
+        println!("{}", Flow::table_header());
+    }
+    tokio::task::spawn(async move {
+        loop {
+            let mut guard = ring.readable_mut().await.unwrap();
+            let ring = guard.get_inner_mut();
+            while let Some(record) = ring.next() {
+                let Some(flow) = Flow::parse(&record) else {
+                    warn!("ignoring a {} byte record", record.len());
+                    continue;
+                };
+                match format {
+                    Format::Table => println!("{}", flow.table_row()),
+                    Format::Json => println!("{}", flow.to_json()),
+                }
+            }
+            guard.clear_ready();
+        }
+    });
+    Ok(())
+}
+
+/// Writes the packets sampled into `SAMPLES` to `writer`, until the program
+/// exits. Each CPU has a perf buffer of its own, read by a task of its own.
+fn spawn_pcap_writer<W: Write + Send + 'static>(
+    mut perf: PerfEventArray<MapData>,
+    writer: PcapWriter<W>,
+    snaplen: u32,
+) -> anyhow::Result<()> {
+    let writer = Arc::new(Mutex::new(writer));
+    // Room for the largest sample, and the padding the kernel adds.
+    let capacity = size_of::<PacketSample>() + snaplen as usize + 8;
+    let cpus = online_cpus().map_err(|(_, error)| error)?;
+    for cpu in cpus {
+        let buf = perf.open(cpu, None)?;
+        let mut buf = AsyncFd::with_interest(buf, Interest::READABLE)?;
+        let writer = Arc::clone(&writer);
+        tokio::task::spawn(async move {
+            let mut buffers = vec![BytesMut::with_capacity(capacity); 16];
+            loop {
+                let mut guard = buf.readable_mut().await.unwrap();
+                let events =
+                    guard.get_inner_mut().read_events(&mut buffers).unwrap();
+                if events.lost > 0 {
+                    warn!("lost {} samples on CPU {cpu}", events.lost);
+                }
+                let received = SystemTime::now();
+                let mut writer = writer.lock().unwrap();
+                for record in &buffers[..events.read] {
+                    let Some(sample) = Sample::parse(record) else {
+                        warn!("ignoring a {} byte sample", record.len());
+                        continue;
+                    };
+                    if let Err(e) = writer.write(received, &sample) {
+                        error!("failed to write to the pcap file: {e}");
+                        return;
+                    }
+                }
+                if let Err(e) = writer.flush() {
+                    error!("failed to write to the pcap file: {e}");
+                    return;
+                }
+                // Anything left over is read on the next iteration.
+                if events.read < buffers.len() {
+                    guard.clear_ready();
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Prints the `n` top talkers counted in `FLOWS` since the previous report.
+fn report_top_talkers(
+    flows: &mut PerCpuHashMap<MapData, FlowKey, FlowStats>,
+    n: usize,
+) -> anyhow::Result<()> {
+    let talkers = top::top(top::drain(flows)?, n);
+    println!("{}", Talker::table_header());
+    for talker in talkers {
+        println!("{}", talker.table_row());
+    }
+    println!();
+    Ok(())
 }
 
 #[tokio::main]
@@ -44,16 +212,76 @@ async fn main() -> Result<(), anyhow::Error> {
             });
         }
     }
+    if opt.top.is_some() {
+        Array::try_from(bpf.map_mut("MODE").unwrap())?.set(
+            0,
+            mode::TOP_TALKERS,
+            0,
+        )?;
+    }
     let program: &mut Xdp =
         bpf.program_mut("xdp_firewall").unwrap().try_into()?;
     program.load()?;
-    program.attach(&opt.iface, XdpMode::default())
-        .context("failed to attach the XDP program with default mode - try changing XdpMode::default() to XdpMode::Skb")?;
+    let ifaces = attach::interfaces(&opt.iface, opt.all)?;
+    let attachments = attach::attach_all(program, &ifaces, opt.mode)?;
+
+    let mut flows: PerCpuHashMap<_, FlowKey, FlowStats> =
+        PerCpuHashMap::try_from(bpf.take_map("FLOWS").unwrap())?;
+    if opt.top.is_none() {
+        spawn_flow_printer(
+            RingBuf::try_from(bpf.take_map("EVENTS").unwrap())?,
+            opt.format,
+        )?;
+    }
+
+    if let Some(path) = &opt.pcap {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        spawn_pcap_writer(
+            PerfEventArray::try_from(bpf.take_map("SAMPLES").unwrap())?,
+            PcapWriter::new(BufWriter::new(file), opt.snaplen)?,
+            opt.snaplen,
+        )?;
+        // Only start sampling once something is reading the samples.
+        let sampling = Sampling {
+            rate: opt.sample_rate,
+            snaplen: opt.snaplen,
+        };
+        Array::try_from(bpf.map_mut("SAMPLING").unwrap())?
+            .set(0, sampling, 0)?;
+        info!(
+            "writing 1 in {} packets to {}",
+            opt.sample_rate,
+            path.display()
+        );
+    }
 
     let ctrl_c = signal::ctrl_c();
     info!("Waiting for Ctrl-C...");
-    ctrl_c.await?;
+    match opt.top {
+        None => ctrl_c.await?,
+        Some(n) => {
+            let period = Duration::from_secs(opt.interval);
+            let mut interval =
+                time::interval_at(Instant::now() + period, period);
+            tokio::pin!(ctrl_c);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        report_top_talkers(&mut flows, n)?;
+                    }
+                    res = &mut ctrl_c => {
+                        res?;
+                        break;
+                    }
+                }
+            }
+        }
+    }
     info!("Exiting...");
+    let program: &mut Xdp =
+        bpf.program_mut("xdp_firewall").unwrap().try_into()?;
+    attach::detach_all(program, attachments);
 
     Ok(())
 }
@@ -0,0 +1,89 @@
+//! Packets sampled into the `SAMPLES` perf buffers, and the pcap files they're
+//! written to.
+//!
+//! The file format is the classic libpcap one, which Wireshark and tcpdump
+//! read: a global header, then a record header and the captured bytes for each
+//! packet.
+
+use std::{
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use xdp_log_common::PacketSample;
+
+/// Identifies a pcap file with microsecond timestamps, in the byte order it was
+/// written in.
+pub const MAGIC: u32 = 0xa1b2c3d4;
+pub const VERSION: (u16, u16) = (2, 4);
+/// `LINKTYPE_ETHERNET`, as every packet starts with an Ethernet header.
+pub const LINKTYPE_ETHERNET: u32 = 1;
+
+/// A [`PacketSample`] and the packet bytes that follow it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample<'a> {
+    /// Length of the whole frame.
+    pub len: u32,
+    /// The first bytes of the frame.
+    pub data: &'a [u8],
+}
+
+impl<'a> Sample<'a> {
+    /// Decodes a perf event. The kernel pads events to a multiple of 8 bytes,
+    /// so `record` may be longer than the sample.
+    pub fn parse(record: &'a [u8]) -> Option<Self> {
+        let header = record.get(..size_of::<PacketSample>())?;
+        // SAFETY: the length was checked above and PacketSample is plain data.
+        let PacketSample { len, cap_len } =
+            unsafe { header.as_ptr().cast::<PacketSample>().read_unaligned() };
+        let data = record[header.len()..].get(..cap_len as usize)?;
+        Some(Self { len, data })
+    }
+}
+
+/// Writes samples to a pcap file.
+pub struct PcapWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the global header, for packets cut to `snaplen` bytes.
+    pub fn new(mut writer: W, snaplen: u32) -> io::Result<Self> {
+        let (major, minor) = VERSION;
+        writer.write_all(&MAGIC.to_ne_bytes())?;
+        writer.write_all(&major.to_ne_bytes())?;
+        writer.write_all(&minor.to_ne_bytes())?;
+        writer.write_all(&0i32.to_ne_bytes())?; // thiszone, always UTC
+        writer.write_all(&0u32.to_ne_bytes())?; // sigfigs, always 0
+        writer.write_all(&snaplen.to_ne_bytes())?;
+        writer.write_all(&LINKTYPE_ETHERNET.to_ne_bytes())?;
+        Ok(Self { writer })
+    }
+
+    /// Writes a record for `sample`, captured at `timestamp`.
+    pub fn write(
+        &mut self,
+        timestamp: SystemTime,
+        sample: &Sample<'_>,
+    ) -> io::Result<()> {
+        let since_epoch =
+            timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        // The format only has room for 32-bit seconds.
+        let seconds = since_epoch.as_secs() as u32;
+        let microseconds = since_epoch.subsec_micros();
+        self.writer.write_all(&seconds.to_ne_bytes())?;
+        self.writer.write_all(&microseconds.to_ne_bytes())?;
+        self.writer
+            .write_all(&(sample.data.len() as u32).to_ne_bytes())?;
+        self.writer.write_all(&sample.len.to_ne_bytes())?;
+        self.writer.write_all(sample.data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
@@ -0,0 +1,119 @@
+//! Top talkers: the flows that sent the most bytes, counted by `xdp_firewall`
+//! in the `FLOWS` map.
+
+use std::{
+    borrow::BorrowMut,
+    net::{IpAddr, Ipv6Addr},
+};
+
+use aya::maps::{MapData, MapError, PerCpuHashMap};
+use xdp_log_common::{FlowKey, FlowStats};
+
+use crate::flow::{Endpoint, Protocol, TCP, UDP};
+
+/// A flow and how much it sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Talker {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub proto: u8,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl Talker {
+    pub fn new(key: &FlowKey, stats: FlowStats) -> Self {
+        let addr = |addr| match key.ip_version {
+            4 => Ipv6Addr::from(addr).to_canonical(),
+            _ => IpAddr::V6(Ipv6Addr::from(addr)),
+        };
+        Self {
+            src: addr(key.src_addr),
+            dst: addr(key.dst_addr),
+            proto: key.proto,
+            src_port: key.src_port,
+            dst_port: key.dst_port,
+            packets: stats.packets,
+            bytes: stats.bytes,
+        }
+    }
+
+    fn has_ports(&self) -> bool {
+        matches!(self.proto, TCP | UDP)
+    }
+
+    /// The source address, with the port for TCP and UDP.
+    pub fn source(&self) -> Endpoint {
+        Endpoint(self.src, self.has_ports().then_some(self.src_port))
+    }
+
+    /// The destination address, with the port for TCP and UDP.
+    pub fn destination(&self) -> Endpoint {
+        Endpoint(self.dst, self.has_ports().then_some(self.dst_port))
+    }
+
+    /// The column headings matching [`Talker::table_row`].
+    pub fn table_header() -> String {
+        format!(
+            "{:<21} {:<21} {:<6} {:>10} {:>12}",
+            "SOURCE", "DESTINATION", "PROTO", "PACKETS", "BYTES"
+        )
+    }
+
+    /// Formats the talker as a line of a table, under
+    /// [`Talker::table_header`].
+    pub fn table_row(&self) -> String {
+        format!(
+            "{:<21} {:<21} {:<6} {:>10} {:>12}",
+            self.source().to_string(),
+            self.destination().to_string(),
+            Protocol(self.proto).to_string(),
+            self.packets,
+            self.bytes,
+        )
+    }
+}
+
+/// The `n` flows that sent the most bytes, most first. Ties go to the flow
+/// with more packets.
+pub fn top(
+    flows: impl IntoIterator<Item = (FlowKey, FlowStats)>,
+    n: usize,
+) -> Vec<Talker> {
+    let mut talkers: Vec<_> = flows
+        .into_iter()
+        .map(|(key, stats)| Talker::new(&key, stats))
+        .collect();
+    talkers.sort_by(|a, b| (b.bytes, b.packets).cmp(&(a.bytes, a.packets)));
+    talkers.truncate(n);
+    talkers
+}
+
+/// Reads every flow in `FLOWS`, summing its counts across CPUs, and empties
+/// the map so that the next read starts from zero.
+///
+/// Packets counted between reading a flow and removing it are lost, which is
+/// close enough for a periodic summary.
+pub fn drain<T: BorrowMut<MapData>>(
+    flows: &mut PerCpuHashMap<T, FlowKey, FlowStats>,
+) -> Result<Vec<(FlowKey, FlowStats)>, MapError> {
+    let mut drained = Vec::new();
+    for entry in flows.iter() {
+        let (key, values) = entry?;
+        let mut total = FlowStats::default();
+        for cpu in values.iter() {
+            total.packets += cpu.packets;
+            total.bytes += cpu.bytes;
+        }
+        drained.push((key, total));
+    }
+    // Removing entries while iterating would restart the iteration, hence the
+    // second pass.
+    for (key, _) in &drained {
+        // The kernel may have evicted the flow since, which is just as good.
+        let _ = flows.remove(key);
+    }
+    Ok(drained)
+}
@@ -0,0 +1,246 @@
+//! Frame builders for the parsing tests, which run `xdp_firewall` with the
+//! `BPF_PROG_TEST_RUN` harness in `example-test-utils` and check the flow
+//! records it emits for each kind of frame, plus a pcap reader for the tests of
+//! `--pcap`. The end-to-end tests in `veth.rs` use the rig in that crate too.
+#![allow(dead_code)]
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use aya::{Ebpf, maps::RingBuf, programs::Xdp};
+use xdp_log::{flow::Flow, pcap::MAGIC};
+
+pub const XDP_ABORTED: u32 = 0;
+pub const XDP_PASS: u32 = 2;
+
+pub fn load() -> Ebpf {
+    let mut bpf = Ebpf::load(aya::include_bytes_aligned!(concat!(
+        env!("OUT_DIR"),
+        "/xdp-log"
+    )))
+    .unwrap();
+    let program: &mut Xdp =
+        bpf.program_mut("xdp_firewall").unwrap().try_into().unwrap();
+    program.load().unwrap();
+    bpf
+}
+
+/// Runs `xdp_firewall` once over `frame`, returning the action it chose and
+/// the flow records it emitted.
+pub fn run(bpf: &mut Ebpf, frame: &[u8]) -> (u32, Vec<Flow>) {
+    let program: &Xdp =
+        bpf.program("xdp_firewall").unwrap().try_into().unwrap();
+    let action = example_test_utils::test_run(program.fd().unwrap(), frame);
+    let mut events = RingBuf::try_from(bpf.map_mut("EVENTS").unwrap()).unwrap();
+    let mut flows = Vec::new();
+    while let Some(record) = events.next() {
+        flows.push(Flow::parse(&record).unwrap());
+    }
+    (action, flows)
+}
+
+/// Like [`run`], for frames that must produce exactly one record.
+pub fn run_one(bpf: &mut Ebpf, frame: &[u8]) -> Flow {
+    let (action, flows) = run(bpf, frame);
+    assert_eq!(action, XDP_PASS, "{frame:02x?}");
+    match flows[..] {
+        [flow] => flow,
+        _ => panic!("expected one record, got {flows:?}"),
+    }
+}
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+pub const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+pub const SRC_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+pub const DST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+
+/// Builds an Ethernet header with the given VLAN tags, each a tag protocol
+/// identifier and a VLAN ID, outermost first.
+pub fn ethernet(tags: &[(u16, u16)], ether_type: u16) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&DST_MAC);
+    frame.extend_from_slice(&SRC_MAC);
+    for &(tpid, vlan_id) in tags {
+        frame.extend_from_slice(&tpid.to_be_bytes());
+        // Priority 5, so that the ID has to be masked out of the TCI.
+        frame.extend_from_slice(&(0xa000 | vlan_id).to_be_bytes());
+    }
+    frame.extend_from_slice(&ether_type.to_be_bytes());
+    frame
+}
+
+pub const ICMP: u8 = 1;
+pub const TCP: u8 = 6;
+pub const UDP: u8 = 17;
+pub const ICMPV6: u8 = 58;
+
+pub const SRC_IP: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 7);
+pub const DST_IP: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+/// Builds an IPv4 header from `SRC_IP` to `DST_IP` followed by `payload`.
+pub fn ipv4(proto: u8, payload: &[u8]) -> Vec<u8> {
+    ipv4_with(&[], 0, proto, payload)
+}
+
+/// The "more fragments" flag, in the same field as the fragment offset.
+pub const MORE_FRAGMENTS: u16 = 0x2000;
+
+/// Like [`ipv4`], with `options`, which must be a multiple of 4 bytes long,
+/// and the given flags and fragment offset field.
+pub fn ipv4_with(
+    options: &[u8],
+    flags_and_offset: u16,
+    proto: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    assert_eq!(options.len() % 4, 0, "options must be padded to 4 bytes");
+    let header_len = 20 + options.len();
+    let ihl = (header_len / 4) as u8;
+    let total_len = (header_len + payload.len()) as u16;
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&[0x40 | ihl, 0]); // version, IHL, DSCP
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]); // identification
+    packet.extend_from_slice(&flags_and_offset.to_be_bytes());
+    packet.extend_from_slice(&[64, proto, 0, 0]); // TTL, protocol, checksum
+    packet.extend_from_slice(&SRC_IP.octets());
+    packet.extend_from_slice(&DST_IP.octets());
+    packet.extend_from_slice(options);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+pub const SRC_IP6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 7);
+pub const DST_IP6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+/// Builds an IPv6 header from `SRC_IP6` to `DST_IP6` followed by `payload`,
+/// which starts with the `next_hdr` header.
+pub fn ipv6(next_hdr: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&[0x60, 0, 0, 0]); // version, class, flow label
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[next_hdr, 64]); // next header, hop limit
+    packet.extend_from_slice(&SRC_IP6.octets());
+    packet.extend_from_slice(&DST_IP6.octets());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+pub const HOP_BY_HOP: u8 = 0;
+pub const ROUTING: u8 = 43;
+pub const FRAGMENT: u8 = 44;
+pub const DESTINATION_OPTIONS: u8 = 60;
+
+/// Builds a hop-by-hop, routing or destination options header that is `len`
+/// bytes long, a multiple of 8, followed by `payload`. The options are all
+/// padding.
+pub fn ipv6_ext(len: usize, next_hdr: u8, payload: &[u8]) -> Vec<u8> {
+    assert!(
+        len >= 8 && len % 8 == 0,
+        "bad extension header length {len}"
+    );
+    let mut packet = vec![next_hdr, (len / 8 - 1) as u8];
+    packet.resize(len, 0); // Pad1 options
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Builds an IPv6 fragment header, followed by `payload`. `offset` is in
+/// 8-byte units.
+pub fn ipv6_fragment(
+    offset: u16,
+    more: bool,
+    next_hdr: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let offset_flags = offset << 3 | u16::from(more);
+    let mut packet = vec![next_hdr, 0];
+    packet.extend_from_slice(&offset_flags.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0x12, 0x34]); // identification
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Builds an empty TCP segment with the given flags.
+pub fn tcp(src_port: u16, dst_port: u16, flags: u8) -> Vec<u8> {
+    let mut segment = Vec::new();
+    segment.extend_from_slice(&src_port.to_be_bytes());
+    segment.extend_from_slice(&dst_port.to_be_bytes());
+    segment.extend_from_slice(&[
+        0, 0, 0, 0, // sequence number
+        0, 0, 0, 0, // acknowledgment number
+        0x50, flags, 0xff, 0xff, // data offset, flags, window
+        0, 0, 0, 0, // checksum, urgent pointer
+    ]);
+    segment
+}
+
+/// Builds an empty UDP datagram.
+pub fn udp(src_port: u16, dst_port: u16) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    datagram.extend_from_slice(&src_port.to_be_bytes());
+    datagram.extend_from_slice(&dst_port.to_be_bytes());
+    datagram.extend_from_slice(&[0, 8, 0, 0]); // length, checksum
+    datagram
+}
+
+/// Builds an ICMP echo request.
+pub fn icmp_echo() -> Vec<u8> {
+    vec![8, 0, 0, 0, 0, 0, 0, 0] // type, code, checksum, id, sequence
+}
+
+/// Builds an ICMPv6 echo request.
+pub fn icmpv6_echo() -> Vec<u8> {
+    vec![128, 0, 0, 0, 0, 0, 0, 0] // type, code, checksum, id, sequence
+}
+
+/// Concatenates headers into a frame.
+pub fn frame(parts: &[&[u8]]) -> Vec<u8> {
+    parts.concat()
+}
+
+/// A packet read back from a pcap file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Record {
+    pub seconds: u32,
+    pub microseconds: u32,
+    pub orig_len: u32,
+    pub data: Vec<u8>,
+}
+
+/// Parses a pcap file written in native byte order, returning the snap length,
+/// the link type and the packets.
+pub fn read_pcap(mut file: &[u8]) -> (u32, u32, Vec<Record>) {
+    let next = |file: &mut &[u8]| {
+        let (field, rest) = file.split_first_chunk().expect("truncated pcap");
+        *file = rest;
+        u32::from_ne_bytes(*field)
+    };
+    assert_eq!(next(&mut file), MAGIC);
+    let version = next(&mut file).to_ne_bytes();
+    assert_eq!(version[..2], 2u16.to_ne_bytes(), "major version");
+    assert_eq!(version[2..], 4u16.to_ne_bytes(), "minor version");
+    assert_eq!(next(&mut file), 0, "thiszone");
+    assert_eq!(next(&mut file), 0, "sigfigs");
+    let snaplen = next(&mut file);
+    let linktype = next(&mut file);
+    let mut records = Vec::new();
+    while !file.is_empty() {
+        let seconds = next(&mut file);
+        let microseconds = next(&mut file);
+        let incl_len = next(&mut file) as usize;
+        let orig_len = next(&mut file);
+        let (data, rest) = file.split_at(incl_len);
+        file = rest;
+        records.push(Record {
+            seconds,
+            microseconds,
+            orig_len,
+            data: data.to_vec(),
+        });
+    }
+    (snaplen, linktype, records)
+}
@@ -0,0 +1,116 @@
+//! Tests for decoding and printing flow records.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use xdp_log::flow::Flow;
+use xdp_log_common::{FlowRecord, fragment, ipv4_mapped};
+
+fn record(record: FlowRecord) -> Vec<u8> {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(
+            (&record as *const FlowRecord).cast::<u8>(),
+            size_of::<FlowRecord>(),
+        )
+    };
+    bytes.to_vec()
+}
+
+fn tcp_syn() -> Flow {
+    Flow::parse(&record(FlowRecord {
+        src_mac: [0x02, 0, 0, 0, 0, 0x02],
+        dst_mac: [0x02, 0, 0, 0, 0, 0x01],
+        src_addr: ipv4_mapped([192, 0, 2, 7]),
+        dst_addr: ipv4_mapped([192, 0, 2, 1]),
+        len: 74,
+        src_port: 40000,
+        dst_port: 443,
+        ip_version: 4,
+        proto: 6,
+        tcp_flags: 0x02,
+        ttl: 64,
+        ..Default::default()
+    }))
+    .unwrap()
+}
+
+#[test]
+fn parse_unmaps_ipv4_addresses() {
+    let flow = tcp_syn();
+    assert_eq!(flow.src, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7)));
+    assert_eq!(flow.dst, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+}
+
+#[test]
+fn parse_rejects_short_records() {
+    let record = record(FlowRecord::default());
+    assert_eq!(Flow::parse(&record[..record.len() - 1]), None);
+}
+
+#[test]
+fn table_row_lines_up_with_header() {
+    let header = Flow::table_header();
+    let row = tcp_syn().table_row();
+    assert_eq!(
+        row,
+        "02:00:00:00:00:02 02:00:00:00:00:01           192.0.2.7:40000       \
+         192.0.2.1:443         tcp     64    74  SYN"
+    );
+    assert_eq!(header.find("INFO"), row.find("SYN"));
+}
+
+#[test]
+fn vlan_ids_are_shown_outermost_first() {
+    let mut raw = FlowRecord {
+        vlan_ids: [100, 20],
+        vlan_count: 2,
+        ip_version: 4,
+        proto: 17,
+        ..Default::default()
+    };
+    let flow = Flow::parse(&record(raw)).unwrap();
+    assert_eq!(flow.vlan_ids(), [100, 20]);
+    assert!(flow.table_row().contains(" 100.20 "));
+    assert!(flow.to_json().contains(r#""vlan_ids":[100,20]"#));
+
+    raw.vlan_count = 1;
+    let flow = Flow::parse(&record(raw)).unwrap();
+    assert_eq!(flow.vlan_ids(), [100]);
+}
+
+#[test]
+fn json_leaves_out_fields_that_do_not_apply() {
+    assert_eq!(
+        tcp_syn().to_json(),
+        r#"{"src_mac":"02:00:00:00:00:02","dst_mac":"02:00:00:00:00:01","src_ip":"192.0.2.7","dst_ip":"192.0.2.1","proto":"tcp","src_port":40000,"dst_port":443,"tcp_flags":["SYN"],"ttl":64,"len":74}"#
+    );
+
+    let ping = Flow {
+        proto: 1,
+        tcp_flags: 0,
+        icmp_type: 8,
+        ..tcp_syn()
+    };
+    assert_eq!(
+        ping.to_json(),
+        r#"{"src_mac":"02:00:00:00:00:02","dst_mac":"02:00:00:00:00:01","src_ip":"192.0.2.7","dst_ip":"192.0.2.1","proto":"icmp","icmp_type":8,"icmp_code":0,"ttl":64,"len":74}"#
+    );
+}
+
+#[test]
+fn later_fragments_have_no_transport_fields() {
+    let flow = Flow::parse(&record(FlowRecord {
+        src_addr: ipv4_mapped([192, 0, 2, 7]),
+        dst_addr: ipv4_mapped([192, 0, 2, 1]),
+        ip_version: 4,
+        proto: 6,
+        fragment: fragment::LATER,
+        ..Default::default()
+    }))
+    .unwrap();
+    assert_eq!(flow.source().to_string(), "192.0.2.7");
+    assert!(flow.table_row().ends_with("  later fragment"));
+    assert!(
+        flow.to_json()
+            .contains(r#""proto":"tcp","fragment":"later""#)
+    );
+}
@@ -0,0 +1,299 @@
+//! Tests for the packet parser in `xdp_firewall`, checking the flow records it
+//! emits for hand-built frames.
+
+mod common;
+
+use std::net::IpAddr;
+
+use common::*;
+use xdp_log_common::fragment;
+
+#[test]
+fn records_every_field() {
+    let mut bpf = load();
+    let frame = frame(&[
+        &ethernet(&[], ETHERTYPE_IPV4),
+        &ipv4(TCP, &tcp(40000, 443, 0x12)),
+    ]);
+
+    let flow = run_one(&mut bpf, &frame);
+
+    assert_eq!(flow.src_mac, SRC_MAC);
+    assert_eq!(flow.dst_mac, DST_MAC);
+    assert_eq!(flow.src, IpAddr::V4(SRC_IP));
+    assert_eq!(flow.dst, IpAddr::V4(DST_IP));
+    assert_eq!(flow.proto, TCP);
+    assert_eq!((flow.src_port, flow.dst_port), (40000, 443));
+    assert_eq!(flow.tcp_flags(), ["SYN", "ACK"]);
+    assert_eq!(flow.ttl, 64);
+    assert_eq!(flow.len, frame.len() as u32);
+    assert!(flow.vlan_ids().is_empty());
+}
+
+#[test]
+fn records_icmp_and_unknown_protocols() {
+    let mut bpf = load();
+
+    let flow = run_one(
+        &mut bpf,
+        &frame(&[&ethernet(&[], ETHERTYPE_IPV4), &ipv4(ICMP, &icmp_echo())]),
+    );
+    assert_eq!((flow.proto, flow.icmp_type, flow.icmp_code), (ICMP, 8, 0));
+
+    // 253 is reserved for experimentation.
+    let flow = run_one(
+        &mut bpf,
+        &frame(&[&ethernet(&[], ETHERTYPE_IPV4), &ipv4(253, &[0; 8])]),
+    );
+    assert_eq!(flow.proto, 253);
+    assert_eq!((flow.src_port, flow.dst_port), (0, 0));
+}
+
+#[test]
+fn records_vlan_ids() {
+    let mut bpf = load();
+    for (tags, ids) in [
+        (&[(ETHERTYPE_VLAN, 100)][..], &[100][..]),
+        (&[(ETHERTYPE_QINQ, 100), (ETHERTYPE_VLAN, 20)], &[100, 20]),
+        // Some switches use 802.1Q for both tags.
+        (&[(ETHERTYPE_VLAN, 4094), (ETHERTYPE_VLAN, 1)], &[4094, 1]),
+    ] {
+        let frame = frame(&[
+            &ethernet(tags, ETHERTYPE_IPV4),
+            &ipv4(UDP, &udp(5353, 53)),
+        ]);
+
+        let flow = run_one(&mut bpf, &frame);
+
+        assert_eq!(flow.vlan_ids(), ids);
+        assert_eq!(flow.src, IpAddr::V4(SRC_IP));
+        assert_eq!((flow.src_port, flow.dst_port), (5353, 53));
+    }
+}
+
+#[test]
+fn passes_what_it_does_not_parse() {
+    let mut bpf = load();
+    for frame in [
+        ethernet(&[(ETHERTYPE_VLAN, 100)], ETHERTYPE_ARP),
+        frame(&[
+            &ethernet(
+                &[
+                    (ETHERTYPE_QINQ, 100),
+                    (ETHERTYPE_VLAN, 20),
+                    (ETHERTYPE_VLAN, 3),
+                ],
+                ETHERTYPE_IPV4,
+            ),
+            &ipv4(UDP, &udp(5353, 53)),
+        ]),
+    ] {
+        assert_eq!(run(&mut bpf, &frame), (XDP_PASS, vec![]), "{frame:02x?}");
+    }
+}
+
+#[test]
+fn aborts_truncated_tags() {
+    let mut bpf = load();
+    let frame = ethernet(&[(ETHERTYPE_QINQ, 100), (ETHERTYPE_VLAN, 20)], 0);
+    // Cut the frame in the middle of the inner tag.
+    let (action, flows) = run(&mut bpf, &frame[..20]);
+    assert_eq!(action, XDP_ABORTED);
+    assert!(flows.is_empty());
+}
+
+/// IPv4 options: a router alert, then no-ops up to a multiple of 4 bytes.
+const ROUTER_ALERT: [u8; 4] = [0x94, 0x04, 0, 0];
+const NOP: u8 = 1;
+
+#[test]
+fn ipv4_options_move_the_transport_header() {
+    let mut bpf = load();
+    let mut longest = [NOP; 40];
+    longest[..4].copy_from_slice(&ROUTER_ALERT);
+    for options in [&ROUTER_ALERT[..], &[NOP; 8], &longest] {
+        for tags in [&[][..], &[(ETHERTYPE_VLAN, 100)]] {
+            let frame = frame(&[
+                &ethernet(tags, ETHERTYPE_IPV4),
+                &ipv4_with(options, 0, TCP, &tcp(40000, 443, 0x02)),
+            ]);
+
+            let flow = run_one(&mut bpf, &frame);
+
+            assert_eq!((flow.src_port, flow.dst_port), (40000, 443));
+            assert_eq!(flow.tcp_flags(), ["SYN"]);
+        }
+    }
+}
+
+#[test]
+fn aborts_bad_ipv4_header_lengths() {
+    let mut bpf = load();
+    let mut too_short =
+        frame(&[&ethernet(&[], ETHERTYPE_IPV4), &ipv4(UDP, &udp(5353, 53))]);
+    too_short[14] = 0x44; // IHL 4, shorter than the fixed header
+    let truncated = frame(&[
+        &ethernet(&[], ETHERTYPE_IPV4),
+        &ipv4_with(&[NOP; 40], 0, UDP, &udp(5353, 53)),
+    ]);
+    // Cut the frame in the middle of the options.
+    let truncated = &truncated[..14 + 20 + 20];
+
+    for frame in [&too_short[..], truncated] {
+        let (action, flows) = run(&mut bpf, frame);
+        assert_eq!(action, XDP_ABORTED, "{frame:02x?}");
+        assert!(flows.is_empty());
+    }
+}
+
+#[test]
+fn only_first_fragments_have_ports() {
+    let mut bpf = load();
+
+    let first = frame(&[
+        &ethernet(&[], ETHERTYPE_IPV4),
+        &ipv4_with(&[], MORE_FRAGMENTS, UDP, &udp(5353, 53)),
+    ]);
+    let flow = run_one(&mut bpf, &first);
+    assert_eq!(flow.fragment, fragment::FIRST);
+    assert_eq!((flow.src_port, flow.dst_port), (5353, 53));
+
+    // The middle of a datagram whose bytes would otherwise be read as ports,
+    // then its end, which is too short to hold a UDP header at all.
+    for (flags_and_offset, payload) in
+        [(MORE_FRAGMENTS | 185, &[0xff; 8][..]), (370, &[0xff; 4])]
+    {
+        let frame = frame(&[
+            &ethernet(&[], ETHERTYPE_IPV4),
+            &ipv4_with(&[], flags_and_offset, UDP, payload),
+        ]);
+        let flow = run_one(&mut bpf, &frame);
+        assert_eq!(flow.fragment, fragment::LATER);
+        assert_eq!(flow.proto, UDP);
+        assert_eq!((flow.src_port, flow.dst_port), (0, 0));
+    }
+}
+
+#[test]
+fn records_ipv6() {
+    let mut bpf = load();
+
+    let frame =
+        frame(&[&ethernet(&[], ETHERTYPE_IPV6), &ipv6(UDP, &udp(5353, 53))]);
+    let flow = run_one(&mut bpf, &frame);
+    assert_eq!(flow.src, IpAddr::V6(SRC_IP6));
+    assert_eq!(flow.dst, IpAddr::V6(DST_IP6));
+    assert_eq!(flow.proto, UDP);
+    assert_eq!((flow.src_port, flow.dst_port), (5353, 53));
+    assert_eq!(flow.ttl, 64);
+    assert_eq!(flow.len, frame.len() as u32);
+    assert_eq!(flow.fragment, fragment::NONE);
+
+    let flow = run_one(
+        &mut bpf,
+        &frame(&[
+            &ethernet(&[(ETHERTYPE_VLAN, 100)], ETHERTYPE_IPV6),
+            &ipv6(ICMPV6, &icmpv6_echo()),
+        ]),
+    );
+    assert_eq!(flow.vlan_ids(), [100]);
+    assert_eq!(flow.proto, ICMPV6);
+    assert_eq!((flow.icmp_type, flow.icmp_code), (128, 0));
+}
+
+#[test]
+fn ipv6_extension_headers_are_skipped() {
+    let mut bpf = load();
+
+    let flow = run_one(
+        &mut bpf,
+        &frame(&[
+            &ethernet(&[], ETHERTYPE_IPV6),
+            &ipv6(
+                HOP_BY_HOP,
+                &ipv6_ext(
+                    8,
+                    ROUTING,
+                    &ipv6_ext(
+                        24,
+                        DESTINATION_OPTIONS,
+                        &ipv6_ext(16, TCP, &tcp(40000, 443, 0x02)),
+                    ),
+                ),
+            ),
+        ]),
+    );
+    assert_eq!(flow.proto, TCP);
+    assert_eq!((flow.src_port, flow.dst_port), (40000, 443));
+    assert_eq!(flow.tcp_flags(), ["SYN"]);
+}
+
+#[test]
+fn only_first_ipv6_fragments_have_ports() {
+    let mut bpf = load();
+
+    let first = frame(&[
+        &ethernet(&[], ETHERTYPE_IPV6),
+        &ipv6(FRAGMENT, &ipv6_fragment(0, true, UDP, &udp(5353, 53))),
+    ]);
+    let flow = run_one(&mut bpf, &first);
+    assert_eq!(flow.fragment, fragment::FIRST);
+    assert_eq!(flow.proto, UDP);
+    assert_eq!((flow.src_port, flow.dst_port), (5353, 53));
+
+    // Bytes that would otherwise be read as ports, and then as the header the
+    // fragment header claims follows.
+    for next_hdr in [UDP, DESTINATION_OPTIONS] {
+        let later = frame(&[
+            &ethernet(&[], ETHERTYPE_IPV6),
+            &ipv6(FRAGMENT, &ipv6_fragment(185, false, next_hdr, &[0xff; 8])),
+        ]);
+        let flow = run_one(&mut bpf, &later);
+        assert_eq!(flow.fragment, fragment::LATER);
+        assert_eq!(flow.proto, next_hdr);
+        assert_eq!((flow.src_port, flow.dst_port), (0, 0));
+    }
+}
+
+#[test]
+fn stops_after_too_many_ipv6_extension_headers() {
+    let mut bpf = load();
+
+    // Nine destination options headers, one more than the parser walks.
+    let mut packet = udp(5353, 53);
+    let mut next_hdr = UDP;
+    for _ in 0..9 {
+        packet = ipv6_ext(8, next_hdr, &packet);
+        next_hdr = DESTINATION_OPTIONS;
+    }
+    let flow = run_one(
+        &mut bpf,
+        &frame(&[
+            &ethernet(&[], ETHERTYPE_IPV6),
+            &ipv6(DESTINATION_OPTIONS, &packet),
+        ]),
+    );
+    assert_eq!(flow.proto, DESTINATION_OPTIONS);
+    assert_eq!((flow.src_port, flow.dst_port), (0, 0));
+}
+
+#[test]
+fn aborts_truncated_ipv6_headers() {
+    let mut bpf = load();
+
+    let too_short =
+        frame(&[&ethernet(&[], ETHERTYPE_IPV6), &ipv6(UDP, &udp(5353, 53))]);
+    let too_short = &too_short[..14 + 30];
+    // A routing header that claims to be longer than the frame.
+    let mut truncated = frame(&[
+        &ethernet(&[], ETHERTYPE_IPV6),
+        &ipv6(ROUTING, &ipv6_ext(8, UDP, &udp(5353, 53))),
+    ]);
+    truncated[14 + 40 + 1] = 4;
+
+    for frame in [too_short, &truncated[..]] {
+        let (action, flows) = run(&mut bpf, frame);
+        assert_eq!(action, XDP_ABORTED, "{frame:02x?}");
+        assert!(flows.is_empty());
+    }
+}
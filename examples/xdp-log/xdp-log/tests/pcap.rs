@@ -0,0 +1,179 @@
+//! Tests for packet sampling: `xdp_firewall` copying packets to `SAMPLES`, and
+//! the pcap files they're written to.
+
+mod common;
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use aya::{
+    Ebpf,
+    maps::{Array, MapData, PerfEventArray, perf::PerfEventArrayBuffer},
+    util::online_cpus,
+};
+use bytes::BytesMut;
+use common::*;
+use xdp_log::pcap::{LINKTYPE_ETHERNET, PcapWriter, Sample};
+use xdp_log_common::Sampling;
+
+#[test]
+fn writes_a_readable_pcap() {
+    let packets: [&[u8]; 2] = [&[0xaa; 60], &[0xbb; 40]];
+    let mut writer = PcapWriter::new(Vec::new(), 64).unwrap();
+    let timestamp = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+    writer
+        .write(
+            timestamp,
+            &Sample {
+                len: 60,
+                data: packets[0],
+            },
+        )
+        .unwrap();
+    writer
+        .write(
+            timestamp,
+            &Sample {
+                len: 1500,
+                data: packets[1],
+            },
+        )
+        .unwrap();
+
+    let (snaplen, linktype, records) = read_pcap(&writer.into_inner());
+
+    assert_eq!(snaplen, 64);
+    assert_eq!(linktype, LINKTYPE_ETHERNET);
+    assert_eq!(
+        records,
+        [
+            Record {
+                seconds: 1_700_000_000,
+                microseconds: 123_456,
+                orig_len: 60,
+                data: packets[0].to_vec(),
+            },
+            Record {
+                seconds: 1_700_000_000,
+                microseconds: 123_456,
+                orig_len: 1500,
+                data: packets[1].to_vec(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn samples_ignore_perf_padding() {
+    // A 1500 byte frame cut to 3 bytes, padded so that the event and the
+    // 4-byte size the kernel puts in front of it are a multiple of 8 bytes.
+    let record = frame(&[
+        &1500u32.to_ne_bytes(),
+        &3u32.to_ne_bytes(),
+        &[1, 2, 3],
+        &[0; 1],
+    ]);
+
+    assert_eq!(
+        Sample::parse(&record),
+        Some(Sample {
+            len: 1500,
+            data: &[1, 2, 3]
+        })
+    );
+    // Cut short in the packet bytes, then in the header.
+    assert_eq!(Sample::parse(&record[..10]), None);
+    assert_eq!(Sample::parse(&record[..4]), None);
+}
+
+/// Pins the test to the CPU it's running on, as `xdp_firewall` counts packets
+/// per CPU.
+fn pin_to_current_cpu() {
+    unsafe {
+        let cpu = libc::sched_getcpu();
+        assert!(cpu >= 0);
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu as usize, &mut set);
+        assert_eq!(
+            libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set),
+            0
+        );
+    }
+}
+
+/// Opens the `SAMPLES` perf buffer of every CPU.
+fn open_samples(bpf: &mut Ebpf) -> Vec<PerfEventArrayBuffer<MapData>> {
+    let mut perf =
+        PerfEventArray::try_from(bpf.take_map("SAMPLES").unwrap()).unwrap();
+    online_cpus()
+        .unwrap()
+        .into_iter()
+        .map(|cpu| perf.open(cpu, None).unwrap())
+        .collect()
+}
+
+fn set_sampling(bpf: &mut Ebpf, rate: u32, snaplen: u32) {
+    Array::try_from(bpf.map_mut("SAMPLING").unwrap())
+        .unwrap()
+        .set(0, Sampling { rate, snaplen }, 0)
+        .unwrap();
+}
+
+/// Reads every event waiting in `buffers`.
+fn read_samples(
+    buffers: &mut [PerfEventArrayBuffer<MapData>],
+) -> Vec<BytesMut> {
+    let mut samples = Vec::new();
+    for buffer in buffers {
+        while buffer.readable() {
+            let mut out = vec![BytesMut::with_capacity(256); 8];
+            let events = buffer.read_events(&mut out).unwrap();
+            assert_eq!(events.lost, 0);
+            samples.extend(out.into_iter().take(events.read));
+        }
+    }
+    samples
+}
+
+#[test]
+fn samples_every_nth_packet_into_a_pcap() {
+    pin_to_current_cpu();
+    let mut bpf = load();
+    let frames: Vec<_> = (0..7)
+        .map(|i| {
+            frame(&[
+                &ethernet(&[], ETHERTYPE_IPV4),
+                &ipv4(UDP, &frame(&[&udp(1000 + i, 53), &[0xff; 100]])),
+            ])
+        })
+        .collect();
+
+    let mut buffers = open_samples(&mut bpf);
+
+    // Nothing is sampled until user space asks for it.
+    run_one(&mut bpf, &frames[0]);
+    assert!(read_samples(&mut buffers).is_empty());
+
+    set_sampling(&mut bpf, 3, 64);
+    for frame in &frames {
+        run_one(&mut bpf, frame);
+    }
+    let samples = read_samples(&mut buffers);
+
+    let mut writer = PcapWriter::new(Vec::new(), 64).unwrap();
+    for record in &samples {
+        let sample = Sample::parse(record).unwrap();
+        writer.write(UNIX_EPOCH, &sample).unwrap();
+    }
+    let (snaplen, _, records) = read_pcap(&writer.into_inner());
+    assert_eq!(snaplen, 64);
+    // The frame run before sampling was turned on isn't counted.
+    let expected: Vec<_> = [&frames[2], &frames[5]]
+        .into_iter()
+        .map(|frame| (frame.len() as u32, frame[..64].to_vec()))
+        .collect();
+    let records: Vec<_> = records
+        .into_iter()
+        .map(|record| (record.orig_len, record.data))
+        .collect();
+    assert_eq!(records, expected);
+}
@@ -0,0 +1,102 @@
+//! Tests for top talkers mode, in which `xdp_firewall` counts packets per flow
+//! in `FLOWS` instead of streaming records.
+
+mod common;
+
+use std::net::IpAddr;
+
+use aya::maps::{Array, PerCpuHashMap};
+use common::*;
+use xdp_log::top::{self, Talker};
+use xdp_log_common::{FlowKey, FlowRecord, FlowStats, ipv4_mapped, mode};
+
+fn key(src: [u8; 4], src_port: u16) -> FlowKey {
+    FlowKey::from(&FlowRecord {
+        src_addr: ipv4_mapped(src),
+        dst_addr: ipv4_mapped(DST_IP.octets()),
+        src_port,
+        dst_port: 443,
+        proto: TCP,
+        ip_version: 4,
+        ..Default::default()
+    })
+}
+
+#[test]
+fn top_sorts_by_bytes_then_packets() {
+    let stats = |packets, bytes| FlowStats { packets, bytes };
+    let flows = [
+        (key([192, 0, 2, 1], 1000), stats(10, 1000)),
+        (key([192, 0, 2, 2], 2000), stats(1, 9000)),
+        (key([192, 0, 2, 3], 3000), stats(20, 1000)),
+        (key([192, 0, 2, 4], 4000), stats(1, 60)),
+    ];
+
+    let talkers = top::top(flows, 3);
+
+    let sources: Vec<_> =
+        talkers.iter().map(|t| t.source().to_string()).collect();
+    assert_eq!(
+        sources,
+        ["192.0.2.2:2000", "192.0.2.3:3000", "192.0.2.1:1000"]
+    );
+    assert_eq!(
+        talkers[0].table_row(),
+        format!(
+            "{:<21} {:<21} {:<6} {:>10} {:>12}",
+            "192.0.2.2:2000", "192.0.2.1:443", "tcp", 1, 9000
+        )
+    );
+}
+
+#[test]
+fn counts_flows_instead_of_streaming() {
+    let mut bpf = load();
+    Array::try_from(bpf.map_mut("MODE").unwrap())
+        .unwrap()
+        .set(0, mode::TOP_TALKERS, 0)
+        .unwrap();
+    let small = frame(&[
+        &ethernet(&[], ETHERTYPE_IPV4),
+        &ipv4(TCP, &tcp(40000, 443, 0x10)),
+    ]);
+    let large = frame(&[
+        &ethernet(&[], ETHERTYPE_IPV4),
+        &ipv4(UDP, &frame(&[&udp(5353, 53), &[0; 1000]])),
+    ]);
+
+    for frame in [&small, &large, &large, &small, &large] {
+        let (action, records) = run(&mut bpf, frame);
+        assert_eq!(action, XDP_PASS);
+        assert!(records.is_empty(), "{records:?}");
+    }
+
+    let mut flows: PerCpuHashMap<_, FlowKey, FlowStats> =
+        PerCpuHashMap::try_from(bpf.map_mut("FLOWS").unwrap()).unwrap();
+    let talkers = top::top(top::drain(&mut flows).unwrap(), 10);
+    assert_eq!(
+        talkers,
+        [
+            Talker {
+                src: IpAddr::V4(SRC_IP),
+                dst: IpAddr::V4(DST_IP),
+                proto: UDP,
+                src_port: 5353,
+                dst_port: 53,
+                packets: 3,
+                bytes: 3 * large.len() as u64,
+            },
+            Talker {
+                src: IpAddr::V4(SRC_IP),
+                dst: IpAddr::V4(DST_IP),
+                proto: TCP,
+                src_port: 40000,
+                dst_port: 443,
+                packets: 2,
+                bytes: 2 * small.len() as u64,
+            },
+        ]
+    );
+    // Draining starts the next report from scratch.
+    assert!(top::drain(&mut flows).unwrap().is_empty());
+}
@@ -0,0 +1,109 @@
+//! End-to-end tests that run `xdp-log` on one end of a veth pair and send it
+//! frames from the other.
+
+mod common;
+
+use std::{fs, thread, time::Duration};
+
+use common::*;
+use example_test_utils::netns::{Netns, XDP_IFACE};
+
+const XDP_LOG: &str = env!("CARGO_BIN_EXE_xdp-log");
+
+fn udp_frame(src_port: u16) -> Vec<u8> {
+    frame(&[
+        &ethernet(&[], ETHERTYPE_IPV4),
+        &ipv4(UDP, &frame(&[&udp(src_port, 53), &[0xff; 100]])),
+    ])
+}
+
+#[test]
+fn prints_each_packet() {
+    let netns = Netns::new();
+    let mut xdp_log =
+        netns.spawn(XDP_LOG, &["--iface", XDP_IFACE, "--format", "json"]);
+
+    netns.send(&udp_frame(5353));
+
+    let line = xdp_log.wait_for(|line| line.contains(r#""src_port":5353"#));
+    assert!(line.contains(r#""src_ip":"192.0.2.7""#), "{line}");
+    assert!(line.contains(r#""dst_port":53"#), "{line}");
+    let rest = xdp_log.stop();
+    assert!(
+        rest.iter()
+            .any(|line| line.contains("detached from veth-xdp")),
+        "{rest:?}"
+    );
+}
+
+#[test]
+fn reports_top_talkers() {
+    let netns = Netns::new();
+    let mut xdp_log = netns.spawn(
+        XDP_LOG,
+        &["--iface", XDP_IFACE, "--top", "5", "--interval", "1"],
+    );
+
+    for _ in 0..3 {
+        netns.send(&udp_frame(5353));
+    }
+
+    // The packets may be split across reports.
+    let mut packets = 0;
+    while packets < 3 {
+        let row = xdp_log.wait_for(|line| line.starts_with("192.0.2.7:5353 "));
+        let columns: Vec<_> = row.split_whitespace().collect();
+        assert_eq!(columns[1..3], ["192.0.2.1:53", "udp"], "{row}");
+        packets += columns[3].parse::<u64>().unwrap();
+    }
+    assert_eq!(packets, 3);
+    xdp_log.stop();
+}
+
+#[test]
+fn writes_samples_to_a_pcap() {
+    let netns = Netns::new();
+    let path = std::env::temp_dir()
+        .join(format!("xdp-log-veth-{}.pcap", std::process::id()));
+    let xdp_log = netns.spawn(
+        XDP_LOG,
+        &[
+            "--iface",
+            XDP_IFACE,
+            "--pcap",
+            path.to_str().unwrap(),
+            "--snaplen",
+            "64",
+        ],
+    );
+    let frames: Vec<_> = (0..3).map(|i| udp_frame(1000 + i)).collect();
+
+    for frame in &frames {
+        netns.send(frame);
+    }
+
+    // Samples are written as they're read, so wait for the last one.
+    let size = 24 + frames.len() * (16 + 64);
+    for _ in 0..100 {
+        if fs::metadata(&path).map(|m| m.len() as usize).unwrap_or(0) >= size {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    xdp_log.stop();
+    let file = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let (snaplen, _, records) = read_pcap(&file);
+    assert_eq!(snaplen, 64);
+    // Samples from different CPUs may be written in any order.
+    let mut records: Vec<_> = records
+        .into_iter()
+        .map(|record| (record.orig_len, record.data))
+        .collect();
+    records.sort();
+    let expected: Vec<_> = frames
+        .iter()
+        .map(|frame| (frame.len() as u32, frame[..64].to_vec()))
+        .collect();
+    assert_eq!(records, expected);
+}